@@ -18,13 +18,13 @@ use ic_cdk::{
     caller, post_upgrade, pre_upgrade, query, storage, update,
 };
 use ic_cdk_timers::TimerId;
-use std::cell::RefCell;
+use std::{cell::RefCell, collections::BTreeMap};
 
 mod cmc;
 mod types;
 use types::{
-    ComponentInfoFromProxy, CycleManagements, InitializeOutput, MetricsSnapshot, RefuelTarget,
-    RegisteredCanisterInRegistry,
+    ComponentInfoFromProxy, CycleManagements, InitializeOutput, MetricsSnapshot, ProxyUpgradeArgs,
+    RefuelTarget, RegisteredCanisterInRegistry,
 };
 
 use crate::types::UpgradeStableState;
@@ -38,6 +38,9 @@ thread_local! {
 
     static METRIC_TIMER_ID: RefCell<Option<(TimerId, u64)>> = RefCell::new(None); // with interval_secs
     static METRICS: RefCell<Vec<MetricsSnapshot>> = RefCell::new(Vec::new());
+
+    // args passed to the proxy's post_upgrade at its next upgrade
+    static PROXY_UPGRADE_ARGS: RefCell<BTreeMap<Principal, ProxyUpgradeArgs>> = RefCell::new(BTreeMap::new());
 }
 
 #[query]
@@ -290,9 +293,39 @@ async fn upgrade_proxies() {
     let _ = install_for_upgrade(vault, VAULT_WASM.to_vec(), vec![])
         .await
         .expect("Failed to upgrade Vault for proxy");
-    let _ = install_for_upgrade(caller_proxy, PROXY_WASM.to_vec(), vec![])
-        .await
-        .expect("Failed to upgrade Proxy for proxy");
+    let upgrade_args = get_proxy_upgrade_args(caller_proxy);
+    let _ = install_for_upgrade(
+        caller_proxy,
+        PROXY_WASM.to_vec(),
+        encode_args((upgrade_args,)).unwrap(),
+    )
+    .await
+    .expect("Failed to upgrade Proxy for proxy");
+    // NOTE: args are applied only once
+    PROXY_UPGRADE_ARGS.with(|m| m.borrow_mut().remove(&caller_proxy));
+}
+
+#[query]
+#[candid_method(query)]
+fn get_proxy_upgrade_args(proxy: Principal) -> Option<ProxyUpgradeArgs> {
+    PROXY_UPGRADE_ARGS.with(|m| m.borrow().get(&proxy).cloned())
+}
+
+/// Set the args to be passed to the proxy at its next upgrade, `None` to clear them
+#[update]
+#[candid_method(update)]
+fn set_proxy_upgrade_args(proxy: Principal, args: Option<ProxyUpgradeArgs>) {
+    // only controllers can change the args
+    assert!(ic_cdk::api::is_controller(&caller()), "Not permitted");
+
+    _set_proxy_upgrade_args(proxy, args);
+}
+
+fn _set_proxy_upgrade_args(proxy: Principal, args: Option<ProxyUpgradeArgs>) {
+    PROXY_UPGRADE_ARGS.with(|m| match args {
+        Some(args) => m.borrow_mut().insert(proxy, args),
+        None => m.borrow_mut().remove(&proxy),
+    });
 }
 
 async fn install_for_upgrade(
@@ -429,6 +462,9 @@ fn pre_upgrade() {
 
     let state = UpgradeStableState {
         registry: get_registry(),
+        proxy_upgrade_args: Some(
+            PROXY_UPGRADE_ARGS.with(|m| m.borrow().iter().map(|(k, v)| (*k, v.clone())).collect()),
+        ),
     };
     storage::stable_save((state,)).expect("Failed to save stable state");
 
@@ -442,6 +478,11 @@ fn post_upgrade() {
     let (state,): (UpgradeStableState,) =
         storage::stable_restore().expect("Failed to restore stable state");
     set_registry(state.registry);
+    state
+        .proxy_upgrade_args
+        .unwrap_or_default()
+        .into_iter()
+        .for_each(|(proxy, args)| _set_proxy_upgrade_args(proxy, Some(args)));
 
     ic_cdk::println!("finish: post_upgrade");
 }
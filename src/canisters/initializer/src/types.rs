@@ -57,7 +57,37 @@ pub struct MetricsSnapshot {
     pub cycles: u128,
 }
 
+#[derive(Clone, Debug, Default, CandidType, serde::Deserialize)]
+pub struct ProxyUpgradeArgs {
+    pub registry: Option<Principal>,
+    pub vault: Option<Principal>,
+    pub db: Option<Principal>,
+    pub scheduler: Option<ProxySchedulerOverrides>,
+    pub funding_guard: Option<ProxyFundingGuardConfig>,
+    pub circuit_breaker: Option<ProxyCircuitBreakerConfig>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, serde::Deserialize)]
+pub struct ProxySchedulerOverrides {
+    pub task_interval_secs: Option<u32>,
+    pub delay_secs: Option<u32>,
+    pub is_rounded_start_time: Option<bool>,
+}
+
+#[derive(Clone, Debug, Default, CandidType, serde::Deserialize)]
+pub struct ProxyFundingGuardConfig {
+    pub proxy_min_cycles: Option<u128>,
+    pub target_min_cycles: Option<u128>,
+}
+
+#[derive(Clone, Debug, CandidType, serde::Deserialize)]
+pub struct ProxyCircuitBreakerConfig {
+    pub failure_threshold: u32,
+    pub open_secs: u64,
+}
+
 #[derive(CandidType, serde::Deserialize)]
 pub struct UpgradeStableState {
     pub registry: Principal,
+    pub proxy_upgrade_args: Option<Vec<(Principal, ProxyUpgradeArgs)>>,
 }
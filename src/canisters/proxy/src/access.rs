@@ -0,0 +1,127 @@
+//! Policy of which callers can call which methods of the target through the proxy
+use std::borrow::Cow;
+
+use candid::{Decode, Encode, Principal};
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub enum MethodPattern {
+    Exact(String),
+    Prefix(String),
+}
+impl MethodPattern {
+    pub fn matches(&self, method: &str) -> bool {
+        match self {
+            MethodPattern::Exact(name) => name == method,
+            MethodPattern::Prefix(prefix) => method.starts_with(prefix.as_str()),
+        }
+    }
+}
+
+/// Callers sharing the same allowed methods, a single caller is a group of one member
+#[derive(
+    Clone, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct CallerGroup {
+    pub name: String,
+    pub members: Vec<Principal>,
+    pub allowed_methods: Vec<MethodPattern>,
+}
+
+/// NOTE: Callers not in any group can call any method unless `is_default_deny`
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct AccessPolicy {
+    pub is_default_deny: bool,
+    pub groups: Vec<CallerGroup>,
+}
+impl AccessPolicy {
+    pub fn is_allowed(&self, caller: &Principal, method: &str) -> bool {
+        let mut groups = self
+            .groups
+            .iter()
+            .filter(|g| g.members.contains(caller))
+            .peekable();
+        if groups.peek().is_none() {
+            return !self.is_default_deny;
+        }
+        groups.any(|g| g.allowed_methods.iter().any(|p| p.matches(method)))
+    }
+
+    pub fn put_group(&mut self, group: CallerGroup) {
+        match self.groups.iter_mut().find(|g| g.name == group.name) {
+            Some(current) => *current = group,
+            None => self.groups.push(group),
+        }
+    }
+
+    pub fn remove_group(&mut self, name: &str) {
+        self.groups.retain(|g| g.name != name);
+    }
+}
+impl ic_stable_structures::Storable for AccessPolicy {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_allowed() {
+        let reader = Principal::from_text("ua42s-gaaaa-aaaal-achcq-cai").unwrap();
+        let admin = Principal::from_text("uh54g-lyaaa-aaaal-achca-cai").unwrap();
+        let other = Principal::from_text("u3zgx-4yaaa-aaaal-achaa-cai").unwrap();
+        let mut policy = AccessPolicy::default();
+        policy.put_group(CallerGroup {
+            name: "readers".to_string(),
+            members: vec![reader, admin],
+            allowed_methods: vec![
+                MethodPattern::Prefix("get_".to_string()),
+                MethodPattern::Exact("proxy_get".to_string()),
+            ],
+        });
+        policy.put_group(CallerGroup {
+            name: "admins".to_string(),
+            members: vec![admin],
+            allowed_methods: vec![MethodPattern::Prefix("".to_string())],
+        });
+
+        assert!(policy.is_allowed(&reader, "get_last_indexed"));
+        assert!(policy.is_allowed(&reader, "proxy_get"));
+        assert!(!policy.is_allowed(&reader, "proxy_get_all"));
+        assert!(!policy.is_allowed(&reader, "index"));
+        assert!(policy.is_allowed(&admin, "index"));
+
+        // callers not in any group
+        assert!(policy.is_allowed(&other, "index"));
+        policy.is_default_deny = true;
+        assert!(!policy.is_allowed(&other, "index"));
+
+        policy.remove_group("admins");
+        assert!(!policy.is_allowed(&admin, "index"));
+        assert_eq!(policy.groups.len(), 1);
+    }
+
+    #[test]
+    fn test_put_group_overwrites_by_name() {
+        let caller = Principal::from_text("ua42s-gaaaa-aaaal-achcq-cai").unwrap();
+        let mut policy = AccessPolicy::default();
+        let mut group = CallerGroup {
+            name: "g".to_string(),
+            members: vec![caller],
+            allowed_methods: vec![],
+        };
+        policy.put_group(group.clone());
+        group.allowed_methods = vec![MethodPattern::Exact("get".to_string())];
+        policy.put_group(group.clone());
+        assert_eq!(policy.groups, vec![group]);
+    }
+}
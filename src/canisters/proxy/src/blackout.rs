@@ -0,0 +1,180 @@
+//! Recurring windows in UTC in which the scheduled indexing is not executed
+const SECS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+impl Weekday {
+    fn of_day(days_since_epoch: u64) -> Self {
+        // NOTE: 1970-01-01 is Thursday
+        match (days_since_epoch + 3) % 7 {
+            0 => Weekday::Mon,
+            1 => Weekday::Tue,
+            2 => Weekday::Wed,
+            3 => Weekday::Thu,
+            4 => Weekday::Fri,
+            5 => Weekday::Sat,
+            _ => Weekday::Sun,
+        }
+    }
+}
+
+/// `[start_secs_of_day, end_secs_of_day)` on `weekdays`, every day if `weekdays` is empty
+/// NOTE: The window is overnight if `end_secs_of_day` < `start_secs_of_day`, `weekdays` applies to the day it starts
+#[derive(
+    Clone, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct BlackoutWindow {
+    pub start_secs_of_day: u32,
+    pub end_secs_of_day: u32,
+    pub weekdays: Vec<Weekday>,
+}
+impl BlackoutWindow {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.start_secs_of_day as u64 >= SECS_PER_DAY
+            || self.end_secs_of_day as u64 >= SECS_PER_DAY
+        {
+            return Err(format!("secs of day must be less than {}", SECS_PER_DAY));
+        }
+        if self.start_secs_of_day == self.end_secs_of_day {
+            return Err("start and end of the window must be different".to_string());
+        }
+        Ok(())
+    }
+
+    /// End of the window containing `now`, if any
+    pub fn end_containing(&self, now: u64) -> Option<u64> {
+        let today = now / SECS_PER_DAY;
+        let day_start = today * SECS_PER_DAY;
+        let secs_of_day = now % SECS_PER_DAY;
+        let (start, end) = (self.start_secs_of_day as u64, self.end_secs_of_day as u64);
+        if start < end {
+            return (self.applies_to(today) && start <= secs_of_day && secs_of_day < end)
+                .then_some(day_start + end);
+        }
+        // overnight
+        if self.applies_to(today) && start <= secs_of_day {
+            return Some(day_start + SECS_PER_DAY + end);
+        }
+        if today > 0 && self.applies_to(today - 1) && secs_of_day < end {
+            return Some(day_start + end);
+        }
+        None
+    }
+
+    fn applies_to(&self, days_since_epoch: u64) -> bool {
+        self.weekdays.is_empty() || self.weekdays.contains(&Weekday::of_day(days_since_epoch))
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    candid::CandidType,
+    candid::Deserialize,
+    serde::Serialize,
+)]
+pub enum BlackoutPolicy {
+    /// The run is skipped and the next run follows the interval
+    #[default]
+    Skip,
+    /// The run is executed once at the end of the window
+    Defer,
+}
+
+/// End of the latest window containing `now`, as windows can overlap
+pub fn blackout_end(windows: &[BlackoutWindow], now: u64) -> Option<u64> {
+    windows.iter().filter_map(|w| w.end_containing(now)).max()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 2024-01-01 00:00:00 UTC, Monday
+    const MON: u64 = 1704067200;
+    const HOUR: u64 = 60 * 60;
+
+    fn window(start_hour: u32, end_hour: u32, weekdays: Vec<Weekday>) -> BlackoutWindow {
+        BlackoutWindow {
+            start_secs_of_day: start_hour * 3600,
+            end_secs_of_day: end_hour * 3600,
+            weekdays,
+        }
+    }
+
+    #[test]
+    fn test_weekday() {
+        assert_eq!(Weekday::of_day(0), Weekday::Thu);
+        assert_eq!(Weekday::of_day(MON / SECS_PER_DAY), Weekday::Mon);
+        assert_eq!(Weekday::of_day(MON / SECS_PER_DAY + 6), Weekday::Sun);
+    }
+
+    #[test]
+    fn test_end_containing() {
+        let daily = window(2, 4, vec![]);
+        assert_eq!(daily.end_containing(MON + HOUR), None);
+        assert_eq!(daily.end_containing(MON + 2 * HOUR), Some(MON + 4 * HOUR));
+        assert_eq!(daily.end_containing(MON + 4 * HOUR), None);
+        assert_eq!(
+            daily.end_containing(MON + SECS_PER_DAY + 3 * HOUR),
+            Some(MON + SECS_PER_DAY + 4 * HOUR)
+        );
+
+        let tuesday = window(2, 4, vec![Weekday::Tue]);
+        assert_eq!(tuesday.end_containing(MON + 3 * HOUR), None);
+        assert_eq!(
+            tuesday.end_containing(MON + SECS_PER_DAY + 3 * HOUR),
+            Some(MON + SECS_PER_DAY + 4 * HOUR)
+        );
+    }
+
+    #[test]
+    fn test_end_containing_overnight() {
+        // from Monday 23:00 to Tuesday 01:00
+        let overnight = window(23, 1, vec![Weekday::Mon]);
+        assert_eq!(
+            overnight.end_containing(MON + 23 * HOUR),
+            Some(MON + SECS_PER_DAY + HOUR)
+        );
+        assert_eq!(
+            overnight.end_containing(MON + SECS_PER_DAY + HOUR / 2),
+            Some(MON + SECS_PER_DAY + HOUR)
+        );
+        assert_eq!(overnight.end_containing(MON + HOUR / 2), None); // started on Sunday
+        assert_eq!(
+            overnight.end_containing(MON + SECS_PER_DAY + 23 * HOUR),
+            None
+        );
+    }
+
+    #[test]
+    fn test_blackout_end() {
+        let windows = vec![window(2, 4, vec![]), window(3, 5, vec![])];
+        assert_eq!(blackout_end(&windows, MON + 3 * HOUR), Some(MON + 5 * HOUR));
+        assert_eq!(blackout_end(&windows, MON + 4 * HOUR), Some(MON + 5 * HOUR));
+        assert_eq!(blackout_end(&windows, MON + 5 * HOUR), None);
+        assert_eq!(blackout_end(&[], MON), None);
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(window(2, 4, vec![]).validate().is_ok());
+        assert!(window(23, 1, vec![]).validate().is_ok());
+        assert!(window(2, 2, vec![]).validate().is_err());
+        assert!(window(2, 24, vec![]).validate().is_err());
+    }
+}
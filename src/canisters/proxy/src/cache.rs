@@ -0,0 +1,186 @@
+//! Cache of responses of the target for methods enabled by the target
+//! NOTE: The cache is on the heap, so it is cleared on upgrades
+use std::collections::BTreeMap;
+
+use sha2::{Digest, Sha256};
+
+pub const MAX_ENTRIES: usize = 256;
+pub const MAX_TOTAL_BYTES: usize = 8 * 1024 * 1024;
+
+type Key = (String, [u8; 32]);
+
+struct Entry {
+    value: Vec<u8>,
+    expires_at: u64,
+    seq: u64,
+}
+
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: u64,
+}
+
+#[derive(Default)]
+pub struct ResponseCache {
+    entries: BTreeMap<Key, Entry>,
+    order: BTreeMap<u64, Key>,
+    next_seq: u64,
+    /// Incremented on `clear`, responses of calls started before it are not inserted
+    generation: u64,
+    total_bytes: usize,
+    hits: u64,
+    misses: u64,
+}
+
+impl ResponseCache {
+    pub fn get(&mut self, method: &str, args: &[u8], now: u64) -> Option<Vec<u8>> {
+        let key = key_of(method, args);
+        match self.entries.get(&key) {
+            Some(entry) if entry.expires_at > now => {
+                self.hits += 1;
+                Some(entry.value.clone())
+            }
+            Some(_) => {
+                self.remove(&key);
+                self.misses += 1;
+                None
+            }
+            None => {
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Insert the response of the call started at `generation`
+    pub fn insert(
+        &mut self,
+        generation: u64,
+        method: &str,
+        args: &[u8],
+        value: Vec<u8>,
+        ttl_secs: u64,
+        now: u64,
+    ) {
+        if generation != self.generation || value.len() > MAX_TOTAL_BYTES {
+            return;
+        }
+        let key = key_of(method, args);
+        self.remove(&key);
+        // evict the oldest entries to keep the cache bounded
+        while self.entries.len() >= MAX_ENTRIES || self.total_bytes + value.len() > MAX_TOTAL_BYTES
+        {
+            let Some((_, oldest)) = self.order.first_key_value() else {
+                break;
+            };
+            let oldest = oldest.clone();
+            self.remove(&oldest);
+        }
+
+        let seq = self.next_seq;
+        self.next_seq += 1;
+        self.total_bytes += value.len();
+        self.order.insert(seq, key.clone());
+        self.entries.insert(
+            key,
+            Entry {
+                value,
+                expires_at: now.saturating_add(ttl_secs),
+                seq,
+            },
+        );
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+        self.total_bytes = 0;
+        self.generation += 1;
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            hits: self.hits,
+            misses: self.misses,
+            entries: self.entries.len() as u64,
+        }
+    }
+
+    fn remove(&mut self, key: &Key) {
+        if let Some(entry) = self.entries.remove(key) {
+            self.order.remove(&entry.seq);
+            self.total_bytes -= entry.value.len();
+        }
+    }
+}
+
+fn key_of(method: &str, args: &[u8]) -> Key {
+    (method.to_string(), Sha256::digest(args).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_and_expire() {
+        let mut cache = ResponseCache::default();
+        assert_eq!(cache.get("get", &[1], 0), None);
+
+        cache.insert(0, "get", &[1], vec![10], 60, 0);
+        assert_eq!(cache.get("get", &[1], 59), Some(vec![10]));
+        assert_eq!(cache.get("get", &[2], 59), None);
+        assert_eq!(cache.get("list", &[1], 59), None);
+        assert_eq!(cache.get("get", &[1], 60), None);
+        assert_eq!(
+            cache.stats(),
+            CacheStats {
+                hits: 1,
+                misses: 4,
+                entries: 0,
+            }
+        );
+    }
+
+    #[test]
+    fn test_bounded() {
+        let mut cache = ResponseCache::default();
+        for i in 0..(MAX_ENTRIES as u64 + 1) {
+            cache.insert(0, "get", &i.to_be_bytes(), vec![0], 60, 0);
+        }
+        assert_eq!(cache.stats().entries, MAX_ENTRIES as u64);
+        // the oldest one is evicted
+        assert_eq!(cache.get("get", &0u64.to_be_bytes(), 0), None);
+        assert_eq!(cache.get("get", &1u64.to_be_bytes(), 0), Some(vec![0]));
+
+        cache.insert(0, "big", &[], vec![0; MAX_TOTAL_BYTES], 60, 0);
+        assert_eq!(cache.stats().entries, 1);
+        assert_eq!(
+            cache.get("big", &[], 0).map(|v| v.len()),
+            Some(MAX_TOTAL_BYTES)
+        );
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut cache = ResponseCache::default();
+        cache.insert(0, "get", &[1], vec![10], 60, 0);
+        cache.clear();
+        assert_eq!(cache.get("get", &[1], 0), None);
+        assert_eq!(cache.stats().entries, 0);
+
+        // responses of calls started before the clear are stale
+        cache.insert(0, "get", &[1], vec![10], 60, 0);
+        assert_eq!(cache.get("get", &[1], 0), None);
+        cache.insert(cache.generation(), "get", &[1], vec![11], 60, 0);
+        assert_eq!(cache.get("get", &[1], 0), Some(vec![11]));
+    }
+}
@@ -0,0 +1,79 @@
+//! Certified data over the execution state of the proxy
+//!
+//! The tree has the following leaves, and its root hash is set as the certified data
+//! - `last_execution_result`: candid encoded `ExecutionResult`
+//! - `last_succeeded`: big-endian u64 of the seconds
+//! - `last_indexed_payload_hash`: sha256 of the payload returned by the last successful indexing, empty if none
+use std::cell::RefCell;
+
+use ic_certified_map::{AsHashTree, RbTree};
+use sha2::{Digest, Sha256};
+
+pub const LAST_EXECUTION_RESULT: &[u8] = b"last_execution_result";
+pub const LAST_SUCCEEDED: &[u8] = b"last_succeeded";
+pub const LAST_INDEXED_PAYLOAD_HASH: &[u8] = b"last_indexed_payload_hash";
+
+type Tree = RbTree<&'static [u8], Vec<u8>>;
+
+thread_local! {
+    static TREE: RefCell<Tree> = RefCell::new(RbTree::new());
+}
+
+pub fn payload_hash(payload: &[u8]) -> Vec<u8> {
+    Sha256::digest(payload).to_vec()
+}
+
+pub fn certify(encoded_result: Vec<u8>, last_succeeded: u64, payload_hash: Vec<u8>) {
+    let tree = build_tree(encoded_result, last_succeeded, payload_hash);
+    ic_cdk::api::set_certified_data(&tree.root_hash());
+    TREE.with(|t| *t.borrow_mut() = tree);
+}
+
+/// CBOR encoded hash tree to be verified with the certificate
+pub fn witness() -> Vec<u8> {
+    TREE.with(|t| encode_witness(&t.borrow()))
+}
+
+fn build_tree(encoded_result: Vec<u8>, last_succeeded: u64, payload_hash: Vec<u8>) -> Tree {
+    let mut tree = RbTree::new();
+    tree.insert(LAST_EXECUTION_RESULT, encoded_result);
+    tree.insert(LAST_SUCCEEDED, last_succeeded.to_be_bytes().to_vec());
+    tree.insert(LAST_INDEXED_PAYLOAD_HASH, payload_hash);
+    tree
+}
+
+fn encode_witness(tree: &Tree) -> Vec<u8> {
+    let mut buf = vec![];
+    // NOTE: self-describing tag as required by the interface spec
+    ciborium::ser::into_writer(
+        &ciborium::tag::Required::<_, 55799>(tree.as_hash_tree()),
+        &mut buf,
+    )
+    .expect("Failed to encode witness");
+    buf
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_witness_reconstructs_root_hash() {
+        let tree = build_tree(vec![1, 2, 3], 100, payload_hash(b"payload"));
+        assert_eq!(tree.as_hash_tree().reconstruct(), tree.root_hash());
+        assert_eq!(
+            tree.get(LAST_SUCCEEDED),
+            Some(&100u64.to_be_bytes().to_vec())
+        );
+
+        let witness = encode_witness(&tree);
+        assert_eq!(&witness[..3], &[0xd9, 0xd9, 0xf7]);
+    }
+
+    #[test]
+    fn test_root_hash_changes_with_state() {
+        let before = build_tree(vec![1], 100, vec![]).root_hash();
+        let after = build_tree(vec![1], 101, vec![]).root_hash();
+        assert_ne!(before, after);
+    }
+}
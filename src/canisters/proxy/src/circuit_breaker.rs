@@ -0,0 +1,263 @@
+//! Circuit breaker around the calls to the target, shared by proxied calls and indexing
+//!
+//! - Closed: calls are forwarded, opens after `failure_threshold` consecutive failures
+//! - Open: calls are rejected until `open_secs` elapses, then moves to HalfOpen
+//! - HalfOpen: a single probe is forwarded, closes on success or opens again on failure
+//! - Outcomes are accepted only in the epoch the call was acquired in, so calls outliving a transition are ignored
+use std::borrow::Cow;
+
+use candid::{Decode, Encode};
+use ic_cdk::api::call::RejectionCode;
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct CircuitBreakerConfig {
+    /// 0 disables the circuit breaker
+    pub failure_threshold: u32,
+    pub open_secs: u64,
+}
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_secs: 300,
+        }
+    }
+}
+impl ic_stable_structures::Storable for CircuitBreakerConfig {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    candid::CandidType,
+    candid::Deserialize,
+    serde::Serialize,
+)]
+pub enum CircuitState {
+    #[default]
+    Closed,
+    Open,
+    HalfOpen,
+}
+
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct CircuitBreaker {
+    pub state: CircuitState,
+    pub consecutive_failures: u32,
+    pub opened_at: u64,
+    pub probe_started_at: Option<u64>,
+    /// Incremented on every transition and probe
+    pub epoch: u64,
+}
+
+impl CircuitBreaker {
+    /// Whether a call can be forwarded now, without changing the state
+    pub fn is_permitted(&self, config: &CircuitBreakerConfig, now: u64) -> bool {
+        if config.failure_threshold == 0 {
+            return true;
+        }
+        match self.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => now >= self.retry_at(config),
+            CircuitState::HalfOpen => self.can_probe(config, now),
+        }
+    }
+
+    /// Take the permission to forward a call, returns the epoch to report the result in by `record_*`
+    pub fn acquire(&mut self, config: &CircuitBreakerConfig, now: u64) -> Option<u64> {
+        if !self.is_permitted(config, now) {
+            return None;
+        }
+        if config.failure_threshold > 0 && self.state != CircuitState::Closed {
+            self.state = CircuitState::HalfOpen;
+            self.probe_started_at = Some(now);
+            self.epoch += 1;
+        }
+        Some(self.epoch)
+    }
+
+    pub fn record_success(&mut self, epoch: u64) {
+        if epoch != self.epoch {
+            return;
+        }
+        if self.state != CircuitState::Closed {
+            self.epoch += 1;
+        }
+        *self = Self {
+            epoch: self.epoch,
+            ..Self::default()
+        };
+    }
+
+    /// Close regardless of the calls in flight
+    pub fn reset(&mut self) {
+        self.record_success(self.epoch);
+    }
+
+    pub fn record_failure(&mut self, config: &CircuitBreakerConfig, now: u64, epoch: u64) {
+        if epoch != self.epoch {
+            return;
+        }
+        self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        if config.failure_threshold == 0 {
+            return;
+        }
+        if self.state == CircuitState::HalfOpen
+            || self.consecutive_failures >= config.failure_threshold
+        {
+            self.state = CircuitState::Open;
+            self.opened_at = now;
+            self.probe_started_at = None;
+            self.epoch += 1;
+        }
+    }
+
+    pub fn retry_at(&self, config: &CircuitBreakerConfig) -> u64 {
+        self.opened_at.saturating_add(config.open_secs)
+    }
+
+    // NOTE: Another probe is permitted if the result of the last one is not reported in time
+    fn can_probe(&self, config: &CircuitBreakerConfig, now: u64) -> bool {
+        match self.probe_started_at {
+            Some(started_at) => now >= started_at.saturating_add(config.open_secs),
+            None => true,
+        }
+    }
+}
+
+/// Rejections explicitly made by the target are its answers, not failures of the target
+pub fn is_failure(code: RejectionCode) -> bool {
+    code != RejectionCode::CanisterReject
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> CircuitBreakerConfig {
+        CircuitBreakerConfig {
+            failure_threshold: 3,
+            open_secs: 60,
+        }
+    }
+
+    #[test]
+    fn test_opens_after_consecutive_failures() {
+        let mut breaker = CircuitBreaker::default();
+        breaker.record_failure(&config(), 0, 0);
+        breaker.record_failure(&config(), 1, 0);
+        breaker.record_success(0);
+        breaker.record_failure(&config(), 2, 0);
+        breaker.record_failure(&config(), 3, 0);
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert_eq!(breaker.acquire(&config(), 3), Some(0));
+
+        breaker.record_failure(&config(), 10, 0);
+        assert_eq!(breaker.state, CircuitState::Open);
+        assert_eq!(breaker.acquire(&config(), 69), None);
+        assert_eq!(breaker.retry_at(&config()), 70);
+    }
+
+    #[test]
+    fn test_half_open_probe() {
+        let mut breaker = CircuitBreaker {
+            state: CircuitState::Open,
+            consecutive_failures: 3,
+            opened_at: 0,
+            probe_started_at: None,
+            epoch: 0,
+        };
+        let probe = breaker.acquire(&config(), 60).unwrap();
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+        // only one probe at a time
+        assert_eq!(breaker.acquire(&config(), 61), None);
+        assert!(!breaker.is_permitted(&config(), 61));
+
+        // failed probe opens again
+        breaker.record_failure(&config(), 62, probe);
+        assert_eq!(breaker.state, CircuitState::Open);
+        assert_eq!(breaker.acquire(&config(), 100), None);
+
+        // successful probe closes
+        let probe = breaker.acquire(&config(), 122).unwrap();
+        breaker.record_success(probe);
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert_eq!(breaker.consecutive_failures, 0);
+        assert_eq!(breaker.probe_started_at, None);
+    }
+
+    #[test]
+    fn test_outcomes_of_stale_calls() {
+        let mut breaker = CircuitBreaker::default();
+        // started while closed
+        let stale = breaker.acquire(&config(), 0).unwrap();
+        for now in 1..4 {
+            let epoch = breaker.acquire(&config(), now).unwrap();
+            breaker.record_failure(&config(), now, epoch);
+        }
+        assert_eq!(breaker.state, CircuitState::Open);
+
+        // a success started before the breaker opened does not close it
+        breaker.record_success(stale);
+        assert_eq!(breaker.state, CircuitState::Open);
+        let probe = breaker.acquire(&config(), 63).unwrap();
+        breaker.record_success(stale);
+        breaker.record_failure(&config(), 64, stale);
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+
+        // nor does a probe which timed out
+        let retry = breaker.acquire(&config(), 123).unwrap();
+        breaker.record_success(probe);
+        assert_eq!(breaker.state, CircuitState::HalfOpen);
+        breaker.record_success(retry);
+        assert_eq!(breaker.state, CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_stale_probe() {
+        let mut breaker = CircuitBreaker {
+            state: CircuitState::HalfOpen,
+            consecutive_failures: 3,
+            opened_at: 0,
+            probe_started_at: Some(60),
+            epoch: 0,
+        };
+        assert_eq!(breaker.acquire(&config(), 119), None);
+        assert!(breaker.acquire(&config(), 120).is_some());
+    }
+
+    #[test]
+    fn test_disabled() {
+        let config = CircuitBreakerConfig {
+            failure_threshold: 0,
+            open_secs: 60,
+        };
+        let mut breaker = CircuitBreaker::default();
+        for now in 0..10 {
+            breaker.record_failure(&config, now, 0);
+        }
+        assert_eq!(breaker.state, CircuitState::Closed);
+        assert_eq!(breaker.acquire(&config, 10), Some(0));
+    }
+
+    #[test]
+    fn test_is_failure() {
+        assert!(!is_failure(RejectionCode::CanisterReject));
+        assert!(is_failure(RejectionCode::CanisterError));
+        assert!(is_failure(RejectionCode::SysTransient));
+    }
+}
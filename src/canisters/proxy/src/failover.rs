@@ -0,0 +1,173 @@
+//! Failover of the scheduled indexing over the target and its standby targets
+//!
+//! - Targets are tried in order until one succeeds, so a rejection fails over to the next one in the same run
+//! - A target failing `failure_threshold` times in a row is tried after the others until `retry_secs` elapses
+//! - A recovered target is preferred again by its order, which fails back to the primary
+use std::{borrow::Cow, collections::BTreeMap};
+
+use candid::{Decode, Encode, Principal};
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct FailoverConfig {
+    /// Tried in order after the primary target
+    pub standby_targets: Vec<Principal>,
+    /// 0 never demotes failing targets
+    pub failure_threshold: u32,
+    pub retry_secs: u64,
+}
+impl Default for FailoverConfig {
+    fn default() -> Self {
+        Self {
+            standby_targets: vec![],
+            failure_threshold: 3,
+            retry_secs: 300,
+        }
+    }
+}
+impl ic_stable_structures::Storable for FailoverConfig {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct TargetHealth {
+    pub consecutive_failures: u32,
+    pub last_failed_at: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Failover {
+    health: BTreeMap<Principal, TargetHealth>,
+}
+
+impl Failover {
+    /// Targets in the order to be tried, the primary first and duplicates removed
+    pub fn candidates(
+        &self,
+        primary: Principal,
+        config: &FailoverConfig,
+        now: u64,
+    ) -> Vec<Principal> {
+        let mut targets = vec![primary];
+        for id in config.standby_targets.iter() {
+            if !targets.contains(id) {
+                targets.push(*id);
+            }
+        }
+        // NOTE: Demoted targets are still tried as the last resort
+        let (available, demoted): (Vec<Principal>, Vec<Principal>) = targets
+            .into_iter()
+            .partition(|id| self.is_available(id, config, now));
+        available.into_iter().chain(demoted).collect()
+    }
+
+    pub fn record_success(&mut self, id: Principal) {
+        self.health.remove(&id);
+    }
+
+    pub fn record_failure(&mut self, id: Principal, now: u64) {
+        let health = self.health.entry(id).or_default();
+        health.consecutive_failures = health.consecutive_failures.saturating_add(1);
+        health.last_failed_at = now;
+    }
+
+    pub fn health(&self) -> Vec<(Principal, TargetHealth)> {
+        self.health.iter().map(|(id, h)| (*id, h.clone())).collect()
+    }
+
+    fn is_available(&self, id: &Principal, config: &FailoverConfig, now: u64) -> bool {
+        match self.health.get(id) {
+            Some(health) => {
+                config.failure_threshold == 0
+                    || health.consecutive_failures < config.failure_threshold
+                    || now >= health.last_failed_at.saturating_add(config.retry_secs)
+            }
+            None => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn principals() -> (Principal, Principal, Principal) {
+        (
+            Principal::from_text("ua42s-gaaaa-aaaal-achcq-cai").unwrap(),
+            Principal::from_text("uh54g-lyaaa-aaaal-achca-cai").unwrap(),
+            Principal::from_text("u3zgx-4yaaa-aaaal-achaa-cai").unwrap(),
+        )
+    }
+
+    fn config(standby_targets: Vec<Principal>) -> FailoverConfig {
+        FailoverConfig {
+            standby_targets,
+            failure_threshold: 2,
+            retry_secs: 60,
+        }
+    }
+
+    #[test]
+    fn test_candidates() {
+        let (primary, standby1, standby2) = principals();
+        let config = config(vec![standby1, primary, standby2]);
+        let failover = Failover::default();
+        assert_eq!(
+            failover.candidates(primary, &config, 0),
+            vec![primary, standby1, standby2]
+        );
+        assert_eq!(
+            failover.candidates(primary, &FailoverConfig::default(), 0),
+            vec![primary]
+        );
+    }
+
+    #[test]
+    fn test_fail_over_and_back() {
+        let (primary, standby1, standby2) = principals();
+        let config = config(vec![standby1, standby2]);
+        let mut failover = Failover::default();
+
+        failover.record_failure(primary, 10);
+        assert_eq!(failover.candidates(primary, &config, 10)[0], primary);
+        failover.record_failure(primary, 20);
+        // demoted after the threshold
+        assert_eq!(
+            failover.candidates(primary, &config, 21),
+            vec![standby1, standby2, primary]
+        );
+        // retried after `retry_secs`
+        assert_eq!(failover.candidates(primary, &config, 80)[0], primary);
+        failover.record_failure(primary, 80);
+        assert_eq!(failover.candidates(primary, &config, 81)[0], standby1);
+
+        failover.record_success(primary);
+        assert_eq!(failover.candidates(primary, &config, 81)[0], primary);
+        assert!(failover.health().is_empty());
+    }
+
+    #[test]
+    fn test_never_demoted() {
+        let (primary, standby1, _) = principals();
+        let config = FailoverConfig {
+            failure_threshold: 0,
+            ..config(vec![standby1])
+        };
+        let mut failover = Failover::default();
+        for now in 0..10 {
+            failover.record_failure(primary, now);
+        }
+        assert_eq!(
+            failover.candidates(primary, &config, 10),
+            vec![primary, standby1]
+        );
+    }
+}
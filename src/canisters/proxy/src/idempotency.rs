@@ -0,0 +1,184 @@
+//! Types to deduplicate proxied calls by the idempotency key given by the caller
+use std::borrow::Cow;
+
+use candid::{Decode, Encode, Principal};
+use ic_cdk::api::call::RejectionCode;
+
+pub const MAX_KEY_LEN: usize = 64;
+pub const MAX_ENTRIES: u64 = 1000;
+pub const TTL_SECS: u64 = 24 * 60 * 60;
+/// NOTE: Calls never completed (e.g. trapped after the call) can be retried after this
+pub const IN_FLIGHT_TIMEOUT_SECS: u64 = 5 * 60;
+pub const MAX_REPLAYABLE_RESPONSE_LEN: usize = 3 * 1024;
+const MAX_REJECTION_MESSAGE_LEN: usize = 512;
+
+#[derive(
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    candid::CandidType,
+    candid::Deserialize,
+    serde::Serialize,
+)]
+pub struct IdempotencyKey {
+    pub caller: Principal,
+    pub key: String,
+}
+impl ic_stable_structures::Storable for IdempotencyKey {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl ic_stable_structures::BoundedStorable for IdempotencyKey {
+    const MAX_SIZE: u32 = 400;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub enum IdempotentOutcome {
+    InFlight,
+    Replied(Vec<u8>),
+    /// Rejected explicitly by the target
+    Rejected(String),
+    /// The call was completed, but the response is too large to be stored
+    NotReplayable,
+}
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct IdempotentEntry {
+    pub seq: u64,
+    pub at: u64,
+    pub outcome: IdempotentOutcome,
+}
+impl IdempotentEntry {
+    pub fn is_expired(&self, now: u64) -> bool {
+        let ttl = match self.outcome {
+            IdempotentOutcome::InFlight => IN_FLIGHT_TIMEOUT_SECS,
+            _ => TTL_SECS,
+        };
+        now >= self.at.saturating_add(ttl)
+    }
+}
+impl ic_stable_structures::Storable for IdempotentEntry {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl ic_stable_structures::BoundedStorable for IdempotentEntry {
+    const MAX_SIZE: u32 = 4096;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// None to release the key, e.g. for calls which did not reach the target as rejected by the circuit breaker
+pub fn outcome_of(
+    result: &Result<(Vec<u8>,), (RejectionCode, String)>,
+    is_called: bool,
+) -> Option<IdempotentOutcome> {
+    if !is_called {
+        return None;
+    }
+    match result {
+        Ok((response,)) if response.len() > MAX_REPLAYABLE_RESPONSE_LEN => {
+            Some(IdempotentOutcome::NotReplayable)
+        }
+        Ok((response,)) => Some(IdempotentOutcome::Replied(response.clone())),
+        // NOTE: Only explicit rejections can follow state changes in the target,
+        //       others (e.g. traps) are rolled back, so retries are forwarded
+        Err((RejectionCode::CanisterReject, msg)) => Some(IdempotentOutcome::Rejected(
+            msg.chars().take(MAX_REJECTION_MESSAGE_LEN).collect(),
+        )),
+        Err(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ic_stable_structures::{BoundedStorable, Storable};
+
+    use super::*;
+
+    #[test]
+    fn test_outcome_of() {
+        assert_eq!(
+            outcome_of(&Ok((vec![1],)), true),
+            Some(IdempotentOutcome::Replied(vec![1]))
+        );
+        assert_eq!(
+            outcome_of(&Ok((vec![0; MAX_REPLAYABLE_RESPONSE_LEN + 1],)), true),
+            Some(IdempotentOutcome::NotReplayable)
+        );
+        assert_eq!(
+            outcome_of(
+                &Err((RejectionCode::CanisterReject, "err".to_string())),
+                true
+            ),
+            Some(IdempotentOutcome::Rejected("err".to_string()))
+        );
+        assert_eq!(
+            outcome_of(
+                &Err((RejectionCode::CanisterError, "trapped".to_string())),
+                true
+            ),
+            None
+        );
+        // rejected by the proxy itself
+        assert_eq!(
+            outcome_of(
+                &Err((RejectionCode::CanisterReject, "open".to_string())),
+                false
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_max_size() {
+        let key = IdempotencyKey {
+            caller: Principal::from_text("ua42s-gaaaa-aaaal-achcq-cai").unwrap(),
+            key: "𝄞".repeat(MAX_KEY_LEN),
+        };
+        assert!(key.to_bytes().len() as u32 <= IdempotencyKey::MAX_SIZE);
+
+        let entries = [
+            outcome_of(&Ok((vec![0; MAX_REPLAYABLE_RESPONSE_LEN],)), true),
+            outcome_of(
+                &Err((RejectionCode::CanisterReject, "𝄞".repeat(10_000))),
+                true,
+            ),
+        ];
+        for outcome in entries {
+            let entry = IdempotentEntry {
+                seq: u64::MAX,
+                at: u64::MAX,
+                outcome: outcome.unwrap(),
+            };
+            assert!(entry.to_bytes().len() as u32 <= IdempotentEntry::MAX_SIZE);
+        }
+    }
+
+    #[test]
+    fn test_is_expired() {
+        let entry = |outcome| IdempotentEntry {
+            seq: 0,
+            at: 100,
+            outcome,
+        };
+        assert!(!entry(IdempotentOutcome::InFlight).is_expired(100 + IN_FLIGHT_TIMEOUT_SECS - 1));
+        assert!(entry(IdempotentOutcome::InFlight).is_expired(100 + IN_FLIGHT_TIMEOUT_SECS));
+        assert!(!entry(IdempotentOutcome::Replied(vec![])).is_expired(100 + TTL_SECS - 1));
+        assert!(entry(IdempotentOutcome::Replied(vec![])).is_expired(100 + TTL_SECS));
+    }
+}
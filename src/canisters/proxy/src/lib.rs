@@ -1,15 +1,48 @@
-use std::{borrow::Cow, cell::RefCell};
+use std::{borrow::Cow, cell::RefCell, collections::BTreeMap, future::Future};
 
 use candid::{candid_method, CandidType, Decode, Encode, Int, Principal};
 use ic_cdk::{
-    api::call::{CallResult, RejectionCode}, post_upgrade, query, update
+    api::{
+        call::{
+            call_with_payment128, msg_cycles_accept128, msg_cycles_available128,
+            msg_cycles_refunded128, CallResult, RejectionCode,
+        },
+        canister_balance128,
+    },
+    post_upgrade, query, update,
 };
 use ic_cdk_timers::TimerId;
-use ic_stable_structures::{memory_manager::{MemoryId, MemoryManager, VirtualMemory}, DefaultMemoryImpl};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl, StableBTreeMap,
+};
 use serde::{Deserialize, Serialize};
 
+mod access;
+mod blackout;
+mod cache;
+mod certification;
+mod circuit_breaker;
+mod failover;
+mod idempotency;
+mod sla;
+mod trigger;
+mod validation;
+use access::{AccessPolicy, CallerGroup};
+use blackout::{BlackoutPolicy, BlackoutWindow};
+use cache::{CacheStats, ResponseCache};
+use circuit_breaker::{CircuitBreaker, CircuitBreakerConfig};
+use failover::{Failover, FailoverConfig, TargetHealth};
+use idempotency::{IdempotencyKey, IdempotentEntry, IdempotentOutcome};
+use sla::{RunOutcome, RunRecord, SlaReport};
+use trigger::{EventTrigger, EventTriggerConfig};
+
 type MemoryType = VirtualMemory<DefaultMemoryImpl>;
 
+const IN_FLIGHT_SKIP_REASON: &str = "another run is in flight, a single run follows it";
+const MAX_PROXY_CALL_LOGS: u64 = 1000;
+const MAX_LOGGED_METHOD_LEN: usize = 128;
+
 #[derive(CandidType, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 struct CallLog {
     canister: Principal,
@@ -25,6 +58,8 @@ pub struct IndexingConfig {
     pub args: Vec<u8>,
     pub delay_secs: Option<u32>,
     pub is_rounded_start_time: Option<bool>,
+    pub blackout_windows: Option<Vec<BlackoutWindow>>,
+    pub blackout_policy: Option<BlackoutPolicy>,
 }
 impl ic_stable_structures::Storable for IndexingConfig {
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
@@ -35,11 +70,16 @@ impl ic_stable_structures::Storable for IndexingConfig {
     }
 }
 
-#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize, serde::Serialize)]
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
 pub struct ExecutionResult {
     pub is_succeeded: bool,
     pub timestamp: u64,
     pub error: Option<Error>,
+    pub skip_reason: Option<String>,
+    /// Target which served the run, the primary or one of the standby targets
+    pub served_by: Option<Principal>,
 }
 impl ic_stable_structures::Storable for ExecutionResult {
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
@@ -50,12 +90,111 @@ impl ic_stable_structures::Storable for ExecutionResult {
     }
 }
 
-#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize, serde::Serialize)]
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
 pub struct Error {
     pub message: String,
     // pub backtrace: String,
 }
 
+#[derive(
+    Clone, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct ProxyCallLog {
+    pub caller: Principal,
+    pub method: String,
+    pub at: u64,
+    pub is_succeeded: bool,
+    pub cycles_forwarded: u128,
+    pub cycles_refunded: u128,
+    pub rejection: Option<String>,
+}
+impl ic_stable_structures::Storable for ProxyCallLog {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl ic_stable_structures::BoundedStorable for ProxyCallLog {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+/// Whether the method of the target is forwarded by `proxy_query` or `proxy_call`
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    candid::CandidType,
+    candid::Deserialize,
+    serde::Serialize,
+)]
+pub enum MethodKind {
+    #[default]
+    Update,
+    Query,
+}
+
+/// How the proxy handles a method of the target, configured by the target
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct MethodConfig {
+    pub kind: MethodKind,
+    /// Responses are cached for the seconds if set, the cache is cleared on every successful indexing
+    pub cache_ttl_secs: Option<u64>,
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize, serde::Serialize)]
+struct MethodConfigs(BTreeMap<String, MethodConfig>);
+impl ic_stable_structures::Storable for MethodConfigs {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+
+/// Minimum cycles balances required to run the scheduled indexing
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize, serde::Serialize)]
+pub struct FundingGuardConfig {
+    pub proxy_min_cycles: Option<u128>,
+    pub target_min_cycles: Option<u128>,
+}
+impl ic_stable_structures::Storable for FundingGuardConfig {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+
+/// Cycles balance of a canister observed by the vault
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, serde::Serialize)]
+pub struct CycleObservation {
+    pub cycles: u128,
+    pub timestamp: u64,
+}
+
+/// Execution state with the certificate and the witness to verify it
+/// NOTE: `certificate` is available only in query calls
+#[derive(Clone, Debug, candid::CandidType, candid::Deserialize, serde::Serialize)]
+pub struct CertifiedExecutionState {
+    pub last_execution_result: ExecutionResult,
+    pub last_succeeded: u64,
+    pub last_indexed_payload_hash: Option<Vec<u8>>,
+    pub certificate: Option<Vec<u8>>,
+    pub witness: Vec<u8>,
+}
+
 #[derive(Clone, Debug, candid::CandidType, candid::Deserialize, serde::Serialize)]
 pub struct ComponentInfo {
     pub target: Principal,
@@ -63,6 +202,25 @@ pub struct ComponentInfo {
     pub db: Principal,
 }
 
+/// Optional argument of `post_upgrade`, applied together with the new wasm
+/// NOTE: Every field is optional so that the initializer can send only what changes
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize, serde::Serialize)]
+pub struct UpgradeArgs {
+    pub registry: Option<Principal>,
+    pub vault: Option<Principal>,
+    pub db: Option<Principal>,
+    pub scheduler: Option<SchedulerOverrides>,
+    pub funding_guard: Option<FundingGuardConfig>,
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+#[derive(Clone, Debug, Default, candid::CandidType, candid::Deserialize, serde::Serialize)]
+pub struct SchedulerOverrides {
+    pub task_interval_secs: Option<u32>,
+    pub delay_secs: Option<u32>,
+    pub is_rounded_start_time: Option<bool>,
+}
+
 thread_local! {
     static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
         RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
@@ -126,10 +284,86 @@ thread_local! {
             0,
          ).unwrap()
     );
+    static FUNDING_GUARD_CONFIG: RefCell<ic_stable_structures::StableCell<FundingGuardConfig, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+            FundingGuardConfig::default(),
+         ).unwrap()
+    );
+    static LAST_INDEXED_PAYLOAD_HASH: RefCell<ic_stable_structures::StableCell<Vec<u8>, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+            Vec::new(),
+         ).unwrap()
+    );
+    static PROXY_CALL_LOGS: RefCell<StableBTreeMap<u64, ProxyCallLog, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+    static METHOD_CONFIGS: RefCell<ic_stable_structures::StableCell<MethodConfigs, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+            MethodConfigs::default(),
+         ).unwrap()
+    );
+    static ACCESS_POLICY: RefCell<ic_stable_structures::StableCell<AccessPolicy, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+            AccessPolicy::default(),
+         ).unwrap()
+    );
+    static DENIED_CALLS: RefCell<ic_stable_structures::StableCell<u64, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+            0,
+         ).unwrap()
+    );
+    static CIRCUIT_BREAKER_CONFIG: RefCell<ic_stable_structures::StableCell<CircuitBreakerConfig, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+            CircuitBreakerConfig::default(),
+         ).unwrap()
+    );
+    static IDEMPOTENT_ENTRIES: RefCell<StableBTreeMap<IdempotencyKey, IdempotentEntry, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+    // seq -> key, to evict the oldest entries
+    static IDEMPOTENT_ENTRY_ORDER: RefCell<StableBTreeMap<u64, IdempotencyKey, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+    static EVENT_TRIGGER_CONFIG: RefCell<ic_stable_structures::StableCell<EventTriggerConfig, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+            EventTriggerConfig::default(),
+        ).unwrap()
+    );
+    static RUN_RECORDS: RefCell<StableBTreeMap<u64, RunRecord, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+        )
+    );
+    static FAILOVER_CONFIG: RefCell<ic_stable_structures::StableCell<FailoverConfig, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+            FailoverConfig::default(),
+        ).unwrap()
+    );
 
     // NOTE: TimerId cannot be stored in stable memory
     // https://github.com/dfinity/cdk-rs/issues/392
     static TIMER_ID: RefCell<Option<TimerId>> = RefCell::new(None);
+    // NOTE: A run deferred by a blackout window is dropped on upgrades
+    static DEFERRED_INDEX_TIMER_ID: RefCell<Option<TimerId>> = RefCell::new(None);
+
+    static RESPONSE_CACHE: RefCell<ResponseCache> = RefCell::new(ResponseCache::default());
+    static CIRCUIT_BREAKER: RefCell<CircuitBreaker> = RefCell::new(CircuitBreaker::default());
+    static FAILOVER: RefCell<Failover> = RefCell::new(Failover::default());
+    static EVENT_TRIGGER: RefCell<EventTrigger> = RefCell::new(EventTrigger::default());
 }
 
 #[query]
@@ -226,6 +460,7 @@ fn init(registry: Principal, target: Principal, db: Principal, vault: Principal)
     _set_vault(vault);
     set_registry(registry);
     _set_initializer(ic_cdk::caller()); // NOTE: Generated by initializer
+    certify_execution_state();
 }
 
 #[update]
@@ -244,156 +479,793 @@ async fn list_logs(target: Principal, from: Int, to: Int) -> Vec<CallLog> {
 
 #[update]
 #[candid_method(update)]
-async fn proxy_call(method: String, args: Vec<u8>) -> CallResult<(Vec<u8>,)> {
+async fn proxy_call(
+    method: String,
+    args: Vec<u8>,
+    idempotency_key: Option<String>,
+) -> CallResult<(Vec<u8>,)> {
     let caller = ic_cdk::caller();
-    let result = _proxy_call(caller, method, args).await;
+    let result = _proxy_call(caller, method, args, 0, idempotency_key).await;
     // _put_call_log(caller).await;
     result
 }
 
-async fn _proxy_call(caller: Principal, method: String, args: Vec<u8>) -> CallResult<(Vec<u8>,)> {
-    if !canister_exists(caller).await {
-        ic_cdk::println!("Unknown canster: {:?}", caller.to_string());
-        return Err((
-            RejectionCode::CanisterReject,
-            format!("Unknown canister: {}", caller.to_string()),
-        ));
+/// Forward the call with the cycles attached by the caller
+/// NOTE: Cycles not accepted by the target are refunded to the caller
+///       The balance of the proxy must cover the attached cycles, they are accepted from the caller after the call
+#[update]
+#[candid_method(update)]
+async fn proxy_call_with_payment(
+    method: String,
+    args: Vec<u8>,
+    idempotency_key: Option<String>,
+) -> CallResult<(Vec<u8>,)> {
+    let caller = ic_cdk::caller();
+    let cycles = msg_cycles_available128();
+    _proxy_call(caller, method, args, cycles, idempotency_key).await
+}
+
+async fn _proxy_call(
+    caller: Principal,
+    method: String,
+    args: Vec<u8>,
+    cycles: u128,
+    idempotency_key: Option<String>,
+) -> CallResult<(Vec<u8>,)> {
+    if let Err(err) = authorize(caller, method.as_str()).await {
+        return reject_proxy_call(caller, method, err);
     }
+    let idempotency_key = idempotency_key.map(|key| IdempotencyKey { caller, key });
+    if let Some(key) = &idempotency_key {
+        let now = ic_cdk::api::time() / (1000 * 1000000);
+        if let Some(result) = begin_idempotent_call(key, now) {
+            // NOTE: Duplicates are not forwarded, the attached cycles are refunded to the caller
+            return result;
+        }
+    }
+    let (result, is_called) = forward_proxy_call(caller, method, args, cycles).await;
+    if let Some(key) = &idempotency_key {
+        finish_idempotent_call(key, idempotency::outcome_of(&result, is_called));
+    }
+    result
+}
+
+/// Forward the call to the target, with whether the target was called
+async fn forward_proxy_call(
+    caller: Principal,
+    method: String,
+    args: Vec<u8>,
+    cycles: u128,
+) -> (CallResult<(Vec<u8>,)>, bool) {
+    // NOTE: Paid calls are always forwarded to the target
+    let cache_ttl_secs = method_config_of(method.as_str())
+        .cache_ttl_secs
+        .filter(|_| cycles == 0);
+    if cache_ttl_secs.is_some() {
+        if let Some(cached) = get_cached_response(method.as_str(), &args) {
+            return (Ok((cached,)), false);
+        }
+    }
+    let Some(circuit_breaker_epoch) = acquire_circuit_breaker() else {
+        return (
+            reject_proxy_call(caller, method, circuit_breaker_open_error()),
+            false,
+        );
+    };
+    // NOTE: The cache can be cleared while the call is in flight, e.g. by the indexing
+    let cache_generation = response_cache_generation();
     ic_cdk::println!("proxy call method: {}", method.as_str());
-    let result: CallResult<(Vec<u8>,)> =
-        ic_cdk::api::call::call(_target(), method.as_str(), (args,)).await;
+    let (result, is_sent) = call_target(_target(), method.as_str(), &args, cycles).await;
+    // NOTE: The cycles are attached from the balance of the proxy, and only the cycles used by the target are
+    //       accepted from the caller after the call. The rest are refunded to the caller with the response.
+    let refunded = match (cycles > 0, is_sent) {
+        (false, _) => 0,
+        (true, true) => msg_cycles_refunded128(),
+        (true, false) => cycles,
+    };
+    if cycles > refunded {
+        msg_cycles_accept128(cycles - refunded);
+    }
     if result.is_err() {
         ic_cdk::println!("Error: {:?}", result);
     }
-    result
+    record_circuit_breaker_outcome(
+        circuit_breaker_epoch,
+        result.as_ref().err().map(|(code, _)| *code),
+    );
+    if let (Some(ttl_secs), Ok((response,))) = (cache_ttl_secs, &result) {
+        put_cached_response(
+            cache_generation,
+            method.as_str(),
+            &args,
+            response.clone(),
+            ttl_secs,
+        );
+    }
+    put_proxy_call_log(ProxyCallLog {
+        caller,
+        method,
+        at: ic_cdk::api::time() / (1000 * 1000000),
+        is_succeeded: result.is_ok(),
+        cycles_forwarded: if is_sent { cycles } else { 0 },
+        cycles_refunded: refunded,
+        rejection: result
+            .as_ref()
+            .err()
+            .map(|(code, msg)| format!("{:?}: {}", code, msg)),
+    });
+    (result, is_sent)
 }
 
-async fn canister_exists(_id: Principal) -> bool {
-    // TODO: payment
-    true
-    //let known = KNOWN_CANISTERS.with(|canisters| canisters.borrow().contains(&id));
-    //if known {
-    //    return true;
-    //}
-    //// TODO: This line can be a single-point-of-failure.
-    //let result: CallResult<(bool,)> = ic_cdk::api::call::call(_registry(), "exists", (id,)).await;
-    //match result {
-    //    Ok((exists,)) => match exists {
-    //        true => {
-    //            KNOWN_CANISTERS.with(|canisters| canisters.borrow_mut().push(id));
-    //            true
-    //        }
-    //        false => false,
-    //    },
-    //    Err(err) => {
-    //        ic_cdk::println!("Error: {:?}", err);
-    //        false
-    //    }
-    //}
+/// Call the target, with whether the call was sent
+/// NOTE: Calls that fail to be sent for other reasons than the balance (e.g. the queue is full) trap when the refund
+///       is read, which rolls back the call, so the caller gets back all the attached cycles
+async fn call_target(
+    target: Principal,
+    method: &str,
+    args: &Vec<u8>,
+    cycles: u128,
+) -> (CallResult<(Vec<u8>,)>, bool) {
+    if let Err(err) = check_payment(canister_balance128(), cycles) {
+        return (Err(err), false);
+    }
+    (
+        call_with_payment128(target, method, (args,), cycles).await,
+        true,
+    )
 }
 
-async fn _put_call_log(caller: Principal) {
-    let result: CallResult<()> =
-        ic_cdk::api::call::call(_db(), "putLog", (caller, _target())).await;
-    match result {
-        Err(e) => ic_cdk::println!("Error: {:?}", e),
-        _ => (),
+/// Check that the balance of the proxy covers the cycles attached to the call
+fn check_payment(balance: u128, cycles: u128) -> Result<(), (RejectionCode, String)> {
+    if balance < cycles {
+        return Err((
+            RejectionCode::SysTransient,
+            format!(
+                "Insufficient cycles in the proxy to attach {} cycles: {}",
+                cycles, balance
+            ),
+        ));
     }
+    Ok(())
 }
 
-#[update]
-#[candid_method(update)]
-fn set_registry(id: Principal) {
-    let res = REGISTRY.with(|registry| registry.borrow_mut().set(id.to_text()));
-    res.unwrap();
+fn reject_proxy_call(
+    caller: Principal,
+    method: String,
+    err: (RejectionCode, String),
+) -> CallResult<(Vec<u8>,)> {
+    // NOTE: The attached cycles are not accepted, so they are refunded to the caller
+    put_proxy_call_log(ProxyCallLog {
+        caller,
+        method,
+        at: ic_cdk::api::time() / (1000 * 1000000),
+        is_succeeded: false,
+        cycles_forwarded: 0,
+        cycles_refunded: 0,
+        rejection: Some(err.1.clone()),
+    });
+    Err(err)
 }
 
-#[query]
-#[candid_method(query)]
-fn last_succeeded() -> u64 {
-    LAST_SUCCEEDED.with(|x| x.borrow().get().clone())
+/// Register the call with the key, or return the result of the call with the same key
+fn begin_idempotent_call(key: &IdempotencyKey, now: u64) -> Option<CallResult<(Vec<u8>,)>> {
+    if key.key.chars().count() > idempotency::MAX_KEY_LEN {
+        return Some(Err((
+            RejectionCode::CanisterReject,
+            format!(
+                "Idempotency key must be at most {} characters",
+                idempotency::MAX_KEY_LEN
+            ),
+        )));
+    }
+    let current = IDEMPOTENT_ENTRIES.with(|m| m.borrow().get(key));
+    match current {
+        Some(entry) if !entry.is_expired(now) => {
+            return Some(match entry.outcome {
+                IdempotentOutcome::Replied(response) => Ok((response,)),
+                IdempotentOutcome::Rejected(msg) => Err((RejectionCode::CanisterReject, msg)),
+                IdempotentOutcome::InFlight => Err((
+                    RejectionCode::CanisterReject,
+                    format!("Call with the same idempotency key is in progress: {}", key.key),
+                )),
+                IdempotentOutcome::NotReplayable => Err((
+                    RejectionCode::CanisterReject,
+                    format!(
+                        "Call with the same idempotency key is already completed, but its response is too large to replay: {}",
+                        key.key
+                    ),
+                )),
+            });
+        }
+        Some(entry) => remove_idempotent_entry(key, entry.seq),
+        None => (),
+    }
+
+    let seq = IDEMPOTENT_ENTRY_ORDER.with(|m| {
+        m.borrow()
+            .last_key_value()
+            .map(|(k, _)| k + 1)
+            .unwrap_or_default()
+    });
+    IDEMPOTENT_ENTRIES.with(|m| {
+        m.borrow_mut().insert(
+            key.clone(),
+            IdempotentEntry {
+                seq,
+                at: now,
+                outcome: IdempotentOutcome::InFlight,
+            },
+        )
+    });
+    IDEMPOTENT_ENTRY_ORDER.with(|m| m.borrow_mut().insert(seq, key.clone()));
+    // keep only the latest entries
+    while IDEMPOTENT_ENTRIES.with(|m| m.borrow().len()) > idempotency::MAX_ENTRIES {
+        let (oldest_seq, oldest) =
+            IDEMPOTENT_ENTRY_ORDER.with(|m| m.borrow().first_key_value().unwrap());
+        remove_idempotent_entry(&oldest, oldest_seq);
+    }
+    None
 }
 
-fn set_last_succeeded(v: u64) {
-    let res = LAST_SUCCEEDED.with(|x| x.borrow_mut().set(v));
-    res.unwrap();
+/// Store the outcome of the call, or forget the key so that the call can be retried
+fn finish_idempotent_call(key: &IdempotencyKey, outcome: Option<IdempotentOutcome>) {
+    let Some(entry) = IDEMPOTENT_ENTRIES.with(|m| m.borrow().get(key)) else {
+        return;
+    };
+    match outcome {
+        Some(outcome) => {
+            IDEMPOTENT_ENTRIES.with(|m| {
+                m.borrow_mut()
+                    .insert(key.clone(), IdempotentEntry { outcome, ..entry })
+            });
+        }
+        None => remove_idempotent_entry(key, entry.seq),
+    }
+}
+
+fn remove_idempotent_entry(key: &IdempotencyKey, seq: u64) {
+    IDEMPOTENT_ENTRIES.with(|m| m.borrow_mut().remove(key));
+    IDEMPOTENT_ENTRY_ORDER.with(|m| m.borrow_mut().remove(&seq));
 }
 
+/// Latest `n` logs of proxied calls, in descending order of time
 #[query]
 #[candid_method(query)]
-fn last_execution_result() -> ExecutionResult {
-    LAST_EXECUTION_RESULT.with(|x| x.borrow().get().clone())
+fn proxy_call_logs(n: u64) -> Vec<ProxyCallLog> {
+    PROXY_CALL_LOGS.with(|m| {
+        let logs = m.borrow();
+        let Some((last, _)) = logs.last_key_value() else {
+            return vec![];
+        };
+        let first = (last + 1).saturating_sub(n);
+        let mut res: Vec<ProxyCallLog> = logs.range(first..).map(|(_, v)| v).collect();
+        res.reverse();
+        res
+    })
 }
 
-fn set_last_execution_result(v: ExecutionResult) {
-    let res = LAST_EXECUTION_RESULT.with(|x| x.borrow_mut().set(v));
-    res.unwrap();
+fn put_proxy_call_log(mut log: ProxyCallLog) {
+    if log.method.chars().count() > MAX_LOGGED_METHOD_LEN {
+        log.method = log.method.chars().take(MAX_LOGGED_METHOD_LEN).collect();
+    }
+    PROXY_CALL_LOGS.with(|m| {
+        let mut logs = m.borrow_mut();
+        let next = logs
+            .last_key_value()
+            .map(|(k, _)| k + 1)
+            .unwrap_or_default();
+        logs.insert(next, log);
+        // keep only the latest logs
+        while logs.len() > MAX_PROXY_CALL_LOGS {
+            let (first, _) = logs.first_key_value().unwrap();
+            logs.remove(&first);
+        }
+    });
 }
 
-#[query]
-#[candid_method(query)]
-fn next_schedule() -> u64 {
-    NEXT_SCHEDULE.with(|f| f.borrow().get().clone())
+/// Forward the call to a query method of the target
+/// NOTE: Only methods configured as `Query` by the target can be forwarded
+#[query(composite = true)]
+#[candid_method(composite_query)]
+async fn proxy_query(method: String, args: Vec<u8>) -> CallResult<(Vec<u8>,)> {
+    let caller = ic_cdk::caller();
+    // NOTE: Denials are not counted in `denied_calls`, as the state of query calls is discarded
+    check_caller(caller).await?;
+    check_access(caller, method.as_str())?;
+    let config = method_config_of(method.as_str());
+    if config.kind != MethodKind::Query {
+        return Err((
+            RejectionCode::CanisterReject,
+            format!("Not a query method: {}", method),
+        ));
+    }
+    // NOTE: The cache is only read here, as the state of query calls is discarded
+    if config.cache_ttl_secs.is_some() {
+        if let Some(cached) = get_cached_response(method.as_str(), &args) {
+            return Ok((cached,));
+        }
+    }
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    if !CIRCUIT_BREAKER.with(|b| b.borrow().is_permitted(&get_circuit_breaker_config(), now)) {
+        return Err(circuit_breaker_open_error());
+    }
+    ic_cdk::println!("proxy query method: {}", method.as_str());
+    ic_cdk::api::call::call(_target(), method.as_str(), (args,)).await
 }
 
-fn set_next_schedule(time: u64) {
-    let res = NEXT_SCHEDULE.with(|f| f.borrow_mut().set(time));
-    res.unwrap();
+fn get_cached_response(method: &str, args: &[u8]) -> Option<Vec<u8>> {
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    RESPONSE_CACHE.with(|c| c.borrow_mut().get(method, args, now))
+}
+
+fn response_cache_generation() -> u64 {
+    RESPONSE_CACHE.with(|c| c.borrow().generation())
+}
+
+fn put_cached_response(
+    generation: u64,
+    method: &str,
+    args: &[u8],
+    response: Vec<u8>,
+    ttl_secs: u64,
+) {
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    RESPONSE_CACHE.with(|c| {
+        c.borrow_mut()
+            .insert(generation, method, args, response, ttl_secs, now)
+    });
+}
+
+fn clear_response_cache() {
+    RESPONSE_CACHE.with(|c| c.borrow_mut().clear());
 }
 
 #[query]
 #[candid_method(query)]
-fn get_indexing_config() -> IndexingConfig {
-    INDEXING_CONFIG.with(|f| f.borrow().get().clone())
+fn circuit_breaker() -> CircuitBreaker {
+    CIRCUIT_BREAKER.with(|b| b.borrow().clone())
 }
 
-fn set_indexing_config(config: IndexingConfig) {
-    let res = INDEXING_CONFIG.with(|f| f.borrow_mut().set(config));
-    res.unwrap();
+#[query]
+#[candid_method(query)]
+fn get_circuit_breaker_config() -> CircuitBreakerConfig {
+    CIRCUIT_BREAKER_CONFIG.with(|c| c.borrow().get().clone())
 }
 
 #[update]
 #[candid_method(update)]
-pub fn start_indexing(task_interval_secs: u32, delay_secs: u32, method: String, args: Vec<u8>) {
-    start_indexing_with_is_rounded(task_interval_secs, delay_secs, false, method, args);
+fn set_circuit_breaker_config(config: CircuitBreakerConfig) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Not permitted");
+    }
+    _set_circuit_breaker_config(config);
 }
-// NOTE: `start_indexing` is kept for backward compatibility, `is_rounded_start_time` is added to the interface
-//       Integrate with `start_indexing` when destructive changes are possible
+
+fn _set_circuit_breaker_config(config: CircuitBreakerConfig) {
+    let res = CIRCUIT_BREAKER_CONFIG.with(|c| c.borrow_mut().set(config));
+    res.unwrap();
+}
+
+/// Close the circuit breaker, e.g. after the target is fixed
 #[update]
 #[candid_method(update)]
-pub fn start_indexing_with_is_rounded(task_interval_secs: u32, delay_secs: u32, is_rounded_start_time: bool, method: String, args: Vec<u8>) {
-    assert!(ic_cdk::caller() == _target(), "Not permitted");
-    assert!(next_schedule() == 0, "Already started");
+fn reset_circuit_breaker() {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Not permitted");
+    }
+    CIRCUIT_BREAKER.with(|b| b.borrow_mut().reset());
+}
 
-    let indexing_config = IndexingConfig {
-        task_interval_secs,
-        method,
-        args,
-        delay_secs: Some(delay_secs),
-        is_rounded_start_time: Some(is_rounded_start_time),
-    };
-    start_indexing_internal(indexing_config.clone());
-    set_indexing_config(indexing_config);
+/// Epoch of the circuit breaker to report the outcome in, None if the call is not permitted
+fn acquire_circuit_breaker() -> Option<u64> {
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    let config = get_circuit_breaker_config();
+    CIRCUIT_BREAKER.with(|b| b.borrow_mut().acquire(&config, now))
 }
 
-fn start_indexing_internal(indexing_config: IndexingConfig) {
-    let current_time_sec = (ic_cdk::api::time() / (1000 * 1000000)) as u32;
-    let IndexingConfig {
-        task_interval_secs,
-        delay_secs,
-        is_rounded_start_time,
-        ..
-    } = indexing_config;
-    let delay = if is_rounded_start_time.is_some() {
-        calculate_delay_secs_from_current_secs(current_time_sec, task_interval_secs, delay_secs.unwrap_or_default())
-    } else {
-        delay_secs.unwrap_or_default()
-    };
+fn record_circuit_breaker_outcome(epoch: u64, rejection: Option<RejectionCode>) {
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    let config = get_circuit_breaker_config();
+    CIRCUIT_BREAKER.with(|b| match rejection {
+        Some(code) if circuit_breaker::is_failure(code) => {
+            b.borrow_mut().record_failure(&config, now, epoch)
+        }
+        _ => b.borrow_mut().record_success(epoch),
+    });
+}
 
-    if delay > 0 {
-        let timer_id = ic_cdk_timers::set_timer(std::time::Duration::from_secs(delay as u64), move || {
-            let timer_id = ic_cdk_timers::set_timer_interval(
-                std::time::Duration::from_secs(task_interval_secs as u64),
+fn circuit_breaker_open_error() -> (RejectionCode, String) {
+    let retry_at = CIRCUIT_BREAKER.with(|b| b.borrow().retry_at(&get_circuit_breaker_config()));
+    (
+        RejectionCode::CanisterReject,
+        format!(
+            "Circuit breaker is open for the target, retry after {}",
+            retry_at
+        ),
+    )
+}
+
+#[query]
+#[candid_method(query)]
+fn cache_stats() -> CacheStats {
+    RESPONSE_CACHE.with(|c| c.borrow().stats())
+}
+
+/// NOTE: Denied calls are counted only in update calls, as the state of query calls is discarded
+async fn authorize(caller: Principal, method: &str) -> CallResult<()> {
+    check_caller(caller).await?;
+    if let Err(err) = check_access(caller, method) {
+        increment_denied_calls();
+        return Err(err);
+    }
+    Ok(())
+}
+
+async fn check_caller(caller: Principal) -> CallResult<()> {
+    if !canister_exists(caller).await {
+        ic_cdk::println!("Unknown canster: {:?}", caller.to_string());
+        return Err((
+            RejectionCode::CanisterReject,
+            format!("Unknown canister: {}", caller.to_string()),
+        ));
+    }
+    Ok(())
+}
+
+fn check_access(caller: Principal, method: &str) -> CallResult<()> {
+    if !get_access_policy().is_allowed(&caller, method) {
+        ic_cdk::println!("Denied: caller = {}, method = {}", caller, method);
+        return Err((
+            RejectionCode::CanisterReject,
+            format!("Method not allowed for {}: {}", caller, method),
+        ));
+    }
+    Ok(())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_access_policy() -> AccessPolicy {
+    ACCESS_POLICY.with(|p| p.borrow().get().clone())
+}
+
+fn set_access_policy(policy: AccessPolicy) {
+    let res = ACCESS_POLICY.with(|p| p.borrow_mut().set(policy));
+    res.unwrap();
+}
+
+#[update]
+#[candid_method(update)]
+fn set_default_deny(is_default_deny: bool) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Not permitted");
+    }
+    let mut policy = get_access_policy();
+    policy.is_default_deny = is_default_deny;
+    set_access_policy(policy);
+}
+
+/// Add the group or overwrite the group of the same name
+#[update]
+#[candid_method(update)]
+fn put_caller_group(group: CallerGroup) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Not permitted");
+    }
+    let mut policy = get_access_policy();
+    policy.put_group(group);
+    set_access_policy(policy);
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_caller_group(name: String) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Not permitted");
+    }
+    let mut policy = get_access_policy();
+    policy.remove_group(name.as_str());
+    set_access_policy(policy);
+}
+
+/// Number of update calls denied by the access policy
+#[query]
+#[candid_method(query)]
+fn denied_calls() -> u64 {
+    DENIED_CALLS.with(|x| *x.borrow().get())
+}
+
+fn increment_denied_calls() {
+    let res = DENIED_CALLS.with(|x| {
+        let current = *x.borrow().get();
+        x.borrow_mut().set(current + 1)
+    });
+    res.unwrap();
+}
+
+#[query]
+#[candid_method(query)]
+fn get_method_configs() -> Vec<(String, MethodConfig)> {
+    METHOD_CONFIGS.with(|m| m.borrow().get().0.clone().into_iter().collect())
+}
+
+/// Configure how a method of the target is handled, `None` to reset to the default
+#[update]
+#[candid_method(update)]
+fn set_method_config(method: String, config: Option<MethodConfig>) {
+    let caller = ic_cdk::caller();
+    if caller != _target() && !ic_cdk::api::is_controller(&caller) {
+        ic_cdk::trap("Not permitted");
+    }
+    _set_method_config(method, config);
+}
+
+fn _set_method_config(method: String, config: Option<MethodConfig>) {
+    let mut configs = METHOD_CONFIGS.with(|m| m.borrow().get().clone());
+    match config {
+        Some(config) => configs.0.insert(method, config),
+        None => configs.0.remove(&method),
+    };
+    let res = METHOD_CONFIGS.with(|m| m.borrow_mut().set(configs));
+    res.unwrap();
+    clear_response_cache();
+}
+
+fn method_config_of(method: &str) -> MethodConfig {
+    METHOD_CONFIGS.with(|m| m.borrow().get().0.get(method).cloned().unwrap_or_default())
+}
+
+async fn canister_exists(_id: Principal) -> bool {
+    // TODO: payment
+    true
+    //let known = KNOWN_CANISTERS.with(|canisters| canisters.borrow().contains(&id));
+    //if known {
+    //    return true;
+    //}
+    //// TODO: This line can be a single-point-of-failure.
+    //let result: CallResult<(bool,)> = ic_cdk::api::call::call(_registry(), "exists", (id,)).await;
+    //match result {
+    //    Ok((exists,)) => match exists {
+    //        true => {
+    //            KNOWN_CANISTERS.with(|canisters| canisters.borrow_mut().push(id));
+    //            true
+    //        }
+    //        false => false,
+    //    },
+    //    Err(err) => {
+    //        ic_cdk::println!("Error: {:?}", err);
+    //        false
+    //    }
+    //}
+}
+
+async fn _put_call_log(caller: Principal) {
+    let result: CallResult<()> =
+        ic_cdk::api::call::call(_db(), "putLog", (caller, _target())).await;
+    match result {
+        Err(e) => ic_cdk::println!("Error: {:?}", e),
+        _ => (),
+    }
+}
+
+#[update]
+#[candid_method(update)]
+fn set_registry(id: Principal) {
+    let res = REGISTRY.with(|registry| registry.borrow_mut().set(id.to_text()));
+    res.unwrap();
+}
+
+#[query]
+#[candid_method(query)]
+fn last_succeeded() -> u64 {
+    LAST_SUCCEEDED.with(|x| x.borrow().get().clone())
+}
+
+fn set_last_succeeded(v: u64) {
+    let res = LAST_SUCCEEDED.with(|x| x.borrow_mut().set(v));
+    res.unwrap();
+}
+
+#[query]
+#[candid_method(query)]
+fn last_execution_result() -> ExecutionResult {
+    LAST_EXECUTION_RESULT.with(|x| x.borrow().get().clone())
+}
+
+fn set_last_execution_result(v: ExecutionResult) {
+    let res = LAST_EXECUTION_RESULT.with(|x| x.borrow_mut().set(v));
+    res.unwrap();
+}
+
+fn last_indexed_payload_hash() -> Option<Vec<u8>> {
+    let hash = LAST_INDEXED_PAYLOAD_HASH.with(|x| x.borrow().get().clone());
+    (!hash.is_empty()).then_some(hash)
+}
+
+fn set_last_indexed_payload_hash(v: Vec<u8>) {
+    let res = LAST_INDEXED_PAYLOAD_HASH.with(|x| x.borrow_mut().set(v));
+    res.unwrap();
+}
+
+#[query]
+#[candid_method(query)]
+fn certified_execution_state() -> CertifiedExecutionState {
+    CertifiedExecutionState {
+        last_execution_result: last_execution_result(),
+        last_succeeded: last_succeeded(),
+        last_indexed_payload_hash: last_indexed_payload_hash(),
+        certificate: ic_cdk::api::data_certificate(),
+        witness: certification::witness(),
+    }
+}
+
+fn certify_execution_state() {
+    certification::certify(
+        Encode!(&last_execution_result()).unwrap(),
+        last_succeeded(),
+        last_indexed_payload_hash().unwrap_or_default(),
+    );
+}
+
+#[query]
+#[candid_method(query)]
+fn next_schedule() -> u64 {
+    NEXT_SCHEDULE.with(|f| f.borrow().get().clone())
+}
+
+fn set_next_schedule(time: u64) {
+    let res = NEXT_SCHEDULE.with(|f| f.borrow_mut().set(time));
+    res.unwrap();
+}
+
+#[query]
+#[candid_method(query)]
+fn get_indexing_config() -> IndexingConfig {
+    INDEXING_CONFIG.with(|f| f.borrow().get().clone())
+}
+
+fn set_indexing_config(config: IndexingConfig) {
+    let res = INDEXING_CONFIG.with(|f| f.borrow_mut().set(config));
+    res.unwrap();
+}
+
+#[query]
+#[candid_method(query)]
+fn get_funding_guard_config() -> FundingGuardConfig {
+    FUNDING_GUARD_CONFIG.with(|f| f.borrow().get().clone())
+}
+
+#[update]
+#[candid_method(update)]
+fn set_funding_guard_config(config: FundingGuardConfig) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Not permitted");
+    }
+    _set_funding_guard_config(config);
+}
+
+fn _set_funding_guard_config(config: FundingGuardConfig) {
+    let res = FUNDING_GUARD_CONFIG.with(|f| f.borrow_mut().set(config));
+    res.unwrap();
+}
+
+#[update]
+#[candid_method(update)]
+pub async fn start_indexing(
+    task_interval_secs: u32,
+    delay_secs: u32,
+    method: String,
+    args: Vec<u8>,
+) {
+    start_indexing_with_is_rounded(task_interval_secs, delay_secs, false, method, args).await;
+}
+// NOTE: `start_indexing` is kept for backward compatibility, `is_rounded_start_time` is added to the interface
+//       Integrate with `start_indexing` when destructive changes are possible
+#[update]
+#[candid_method(update)]
+pub async fn start_indexing_with_is_rounded(
+    task_interval_secs: u32,
+    delay_secs: u32,
+    is_rounded_start_time: bool,
+    method: String,
+    args: Vec<u8>,
+) {
+    assert!(ic_cdk::caller() == _target(), "Not permitted");
+    assert!(next_schedule() == 0, "Already started");
+    if let Err(msg) = _validate_indexing(method.as_str(), &args).await {
+        ic_cdk::trap(&format!("Invalid indexing config: {}", msg));
+    }
+    // NOTE: Check again as another call may have started indexing during the validation
+    assert!(next_schedule() == 0, "Already started");
+
+    let current = get_indexing_config();
+    let indexing_config = IndexingConfig {
+        task_interval_secs,
+        method,
+        args,
+        delay_secs: Some(delay_secs),
+        is_rounded_start_time: Some(is_rounded_start_time),
+        // NOTE: Blackout windows can be set before starting
+        blackout_windows: current.blackout_windows,
+        blackout_policy: current.blackout_policy,
+    };
+    start_indexing_internal(indexing_config.clone());
+    set_indexing_config(indexing_config);
+}
+
+/// Set the windows in which the scheduled indexing is not executed, an empty list disables them
+#[update]
+#[candid_method(update)]
+fn set_blackout_windows(windows: Vec<BlackoutWindow>, policy: BlackoutPolicy) {
+    let caller = ic_cdk::caller();
+    if caller != _target() && !ic_cdk::api::is_controller(&caller) {
+        ic_cdk::trap("Not permitted");
+    }
+    for window in windows.iter() {
+        if let Err(msg) = window.validate() {
+            ic_cdk::trap(&format!("Invalid blackout window: {}", msg));
+        }
+    }
+    set_indexing_config(IndexingConfig {
+        blackout_windows: Some(windows),
+        blackout_policy: Some(policy),
+        ..get_indexing_config()
+    });
+}
+
+/// Validate the indexing method and args without starting the indexing
+#[update]
+#[candid_method(update)]
+async fn validate_indexing(method: String, args: Vec<u8>) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+    if caller != _target() && !ic_cdk::api::is_controller(&caller) {
+        ic_cdk::trap("Not permitted");
+    }
+    _validate_indexing(method.as_str(), &args).await
+}
+
+async fn _validate_indexing(method: &str, args: &[u8]) -> Result<(), String> {
+    validation::validate_args(args)?;
+    match candid_interface_of_target().await {
+        Some(did) => validation::validate_method(did.as_str(), method),
+        None => Ok(()), // NOTE: The method cannot be checked if the target does not expose its interface
+    }
+}
+
+/// Candid interface of the target, None if the target does not export it
+/// NOTE: The `candid:service` metadata is readable only through `read_state` of the HTTP interface, not by canisters,
+/// so the interface is read by `__get_candid_interface_tmp_hack` exported by canisters of the ic-cdk macros.
+/// Targets without it are validated only by their args.
+async fn candid_interface_of_target() -> Option<String> {
+    let result: CallResult<(String,)> =
+        ic_cdk::api::call::call(_target(), "__get_candid_interface_tmp_hack", ()).await;
+    match result {
+        Ok((did,)) => Some(did),
+        Err(err) => {
+            ic_cdk::println!("Skip probing the candid interface of the target: {:?}", err);
+            None
+        }
+    }
+}
+
+fn start_indexing_internal(indexing_config: IndexingConfig) {
+    let current_time_sec = (ic_cdk::api::time() / (1000 * 1000000)) as u32;
+    let IndexingConfig {
+        task_interval_secs,
+        delay_secs,
+        is_rounded_start_time,
+        ..
+    } = indexing_config;
+    let delay = if is_rounded_start_time.is_some() {
+        calculate_delay_secs_from_current_secs(current_time_sec, task_interval_secs, delay_secs.unwrap_or_default())
+    } else {
+        delay_secs.unwrap_or_default()
+    };
+
+    if delay > 0 {
+        let timer_id = ic_cdk_timers::set_timer(std::time::Duration::from_secs(delay as u64), move || {
+            let timer_id = ic_cdk_timers::set_timer_interval(
+                std::time::Duration::from_secs(task_interval_secs as u64),
                 || {
                     ic_cdk::spawn(async move { index().await });
                 },
@@ -425,28 +1297,425 @@ async fn index() {
     let config = get_indexing_config();
     let current_time_sec = (ic_cdk::api::time() / (1000 * 1000000)) as u32;
     set_next_schedule((current_time_sec + config.task_interval_secs) as u64);
+    if get_event_trigger_config().is_timer_disabled {
+        return;
+    }
+    run_index(config, false).await;
+}
+
+/// Run the indexing once and record it, forced runs are not skipped by blackout windows and the funding guard
+/// NOTE: A run requested while another is in flight is coalesced into a single run after it, and not recorded
+async fn run_index(config: IndexingConfig, is_forced: bool) -> ExecutionResult {
+    let Some(trigger_started_at) = start_event_trigger_run() else {
+        ic_cdk::println!("Skip indexing: {}", IN_FLIGHT_SKIP_REASON);
+        return ExecutionResult {
+            timestamp: ic_cdk::api::time() / (1000 * 1000000),
+            skip_reason: Some(IN_FLIGHT_SKIP_REASON.to_string()),
+            ..Default::default()
+        };
+    };
+    let started_at = ic_cdk::api::time();
+    let result = _run_index(config, is_forced).await;
+    record_run(started_at, &result);
+    finish_event_trigger_run(trigger_started_at);
+    result
+}
+
+async fn _run_index(config: IndexingConfig, is_forced: bool) -> ExecutionResult {
+    if !is_forced {
+        if let Some(reason) = blackout_skip_reason(&config) {
+            ic_cdk::println!("Skip indexing: {}", reason);
+            return record_skipped_execution(reason);
+        }
+        if let Some(reason) = underfunded_reason().await {
+            ic_cdk::println!("Skip indexing: {}", reason);
+            return record_skipped_execution(reason);
+        }
+    }
+    execute_index(config, is_forced).await
+}
+
+fn blackout_skip_reason(config: &IndexingConfig) -> Option<String> {
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    let windows = config.blackout_windows.as_deref().unwrap_or_default();
+    let end = blackout::blackout_end(windows, now)?;
+    match config.blackout_policy.unwrap_or_default() {
+        BlackoutPolicy::Skip => Some(format!("in blackout window until {}", end)),
+        BlackoutPolicy::Defer => {
+            defer_index(end - now);
+            Some(format!("deferred to {} by blackout window", end))
+        }
+    }
+}
+
+/// Run the indexing once after the delay, ticks deferred in the same window are coalesced into one run
+fn defer_index(delay_secs: u64) {
+    if DEFERRED_INDEX_TIMER_ID.with(|f| f.borrow().is_some()) {
+        return;
+    }
+    let timer_id = ic_cdk_timers::set_timer(std::time::Duration::from_secs(delay_secs), || {
+        DEFERRED_INDEX_TIMER_ID.with(|f| *f.borrow_mut() = None);
+        ic_cdk::spawn(async move {
+            run_index(get_indexing_config(), false).await;
+        });
+    });
+    DEFERRED_INDEX_TIMER_ID.with(|f| *f.borrow_mut() = Some(timer_id));
+}
+
+fn record_run(started_at: u64, result: &ExecutionResult) {
+    let outcome = if result.skip_reason.is_some() {
+        RunOutcome::Skipped
+    } else if result.is_succeeded {
+        RunOutcome::Succeeded
+    } else {
+        RunOutcome::Failed
+    };
+    let latency_ms = match outcome {
+        RunOutcome::Skipped => None,
+        _ => Some((ic_cdk::api::time() - started_at) / 1_000_000),
+    };
+    put_run_record(RunRecord {
+        at: started_at / (1000 * 1000000),
+        outcome,
+        latency_ms,
+        served_by: result.served_by,
+    });
+}
+
+fn put_run_record(record: RunRecord) {
+    let now = record.at;
+    RUN_RECORDS.with(|m| {
+        let mut records = m.borrow_mut();
+        let next = records
+            .last_key_value()
+            .map(|(k, _)| k + 1)
+            .unwrap_or_default();
+        records.insert(next, record);
+        // keep only the records in the longest window
+        while let Some((first, oldest)) = records.first_key_value() {
+            if records.len() <= sla::MAX_RECORDS
+                && now.saturating_sub(oldest.at) < sla::RETENTION_SECS
+            {
+                break;
+            }
+            records.remove(&first);
+        }
+    });
+}
+
+/// Statistics of the runs of the indexing in rolling windows of 1h, 24h, 7d and 30d
+/// NOTE: Runs by `trigger_index_now` and `notify_update` are included
+#[query]
+#[candid_method(query)]
+fn sla_report() -> SlaReport {
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    _sla_report(now)
+}
+
+/// Latest runs of the scheduled indexing with the targets which served them, newest first
+#[query]
+#[candid_method(query)]
+fn run_records(n: u64) -> Vec<RunRecord> {
+    RUN_RECORDS.with(|m| {
+        let records = m.borrow();
+        let Some((last, _)) = records.last_key_value() else {
+            return vec![];
+        };
+        let first = (last + 1).saturating_sub(n);
+        let mut res: Vec<RunRecord> = records.range(first..).map(|(_, v)| v).collect();
+        res.reverse();
+        res
+    })
+}
+
+fn _sla_report(now: u64) -> SlaReport {
+    RUN_RECORDS.with(|m| sla::report(m.borrow().iter().map(|(_, r)| r), now))
+}
+
+/// Trigger the indexing on updates of upstream data, debounced and coalesced by the event trigger
+/// NOTE: The run is started by a timer, so the notifier does not wait for it
+#[update]
+#[candid_method(update)]
+fn notify_update() -> Option<u64> {
+    let caller = ic_cdk::caller();
+    let config = get_event_trigger_config();
+    if caller != _target()
+        && !ic_cdk::api::is_controller(&caller)
+        && !config.notifiers.contains(&caller)
+    {
+        ic_cdk::trap("Not permitted");
+    }
+    assert!(
+        get_indexing_config().task_interval_secs > 0,
+        "indexing_config is not yet set"
+    );
+
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    let run_at = EVENT_TRIGGER.with(|f| f.borrow_mut().notify(config.min_gap_secs, now));
+    if let Some(run_at) = run_at {
+        schedule_notified_index(run_at - now);
+    }
+    run_at
+}
+
+fn schedule_notified_index(delay_secs: u64) {
+    ic_cdk_timers::set_timer(std::time::Duration::from_secs(delay_secs), || {
+        ic_cdk::spawn(async move {
+            run_index(get_indexing_config(), false).await;
+        });
+    });
+}
 
-    let result: CallResult<(Option<Vec<u8>>,)> =
-        ic_cdk::api::call::call(_target(), config.method.as_str(), (config.args,)).await;
+/// None if a run is in flight
+fn start_event_trigger_run() -> Option<u64> {
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    EVENT_TRIGGER
+        .with(|f| f.borrow_mut().start(now))
+        .then_some(now)
+}
+
+fn finish_event_trigger_run(started_at: u64) {
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    let min_gap_secs = get_event_trigger_config().min_gap_secs;
+    let run_at = EVENT_TRIGGER.with(|f| f.borrow_mut().finish(started_at, min_gap_secs, now));
+    if let Some(run_at) = run_at {
+        schedule_notified_index(run_at - now);
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn event_trigger() -> EventTrigger {
+    EVENT_TRIGGER.with(|f| f.borrow().clone())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_event_trigger_config() -> EventTriggerConfig {
+    EVENT_TRIGGER_CONFIG.with(|f| f.borrow().get().clone())
+}
+
+#[update]
+#[candid_method(update)]
+fn set_event_trigger_config(config: EventTriggerConfig) {
+    let caller = ic_cdk::caller();
+    if caller != _target() && !ic_cdk::api::is_controller(&caller) {
+        ic_cdk::trap("Not permitted");
+    }
+    let res = EVENT_TRIGGER_CONFIG.with(|f| f.borrow_mut().set(config));
+    res.unwrap();
+}
+
+async fn underfunded_reason() -> Option<String> {
+    let guard = get_funding_guard_config();
+    let target_cycles = match guard.target_min_cycles {
+        Some(_) => observed_target_cycles().await,
+        None => None,
+    };
+    funding_skip_reason(&guard, canister_balance128(), target_cycles)
+}
+
+async fn observed_target_cycles() -> Option<u128> {
+    let result: CallResult<(Option<CycleObservation>,)> =
+        ic_cdk::api::call::call(_vault(), "observed_cycles_of", (_target(),)).await;
+    match result {
+        Ok((observation,)) => observation.map(|o| o.cycles),
+        Err(err) => {
+            // NOTE: Do not block indexing when the vault is unavailable
+            ic_cdk::println!("Error: {:?}", err);
+            None
+        }
+    }
+}
+
+fn funding_skip_reason(
+    guard: &FundingGuardConfig,
+    proxy_cycles: u128,
+    target_cycles: Option<u128>,
+) -> Option<String> {
+    if let Some(min) = guard.proxy_min_cycles {
+        if proxy_cycles < min {
+            return Some(format!(
+                "proxy is underfunded: balance={}, min={}",
+                proxy_cycles, min
+            ));
+        }
+    }
+    if let (Some(min), Some(cycles)) = (guard.target_min_cycles, target_cycles) {
+        if cycles < min {
+            return Some(format!(
+                "target is underfunded: balance={}, min={}",
+                cycles, min
+            ));
+        }
+    }
+    None
+}
+
+/// NOTE: The circuit breaker guards only the primary target, forced runs are not blocked by it
+async fn execute_index(config: IndexingConfig, is_forced: bool) -> ExecutionResult {
+    let primary = _target();
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    let candidates = FAILOVER.with(|f| f.borrow().candidates(primary, &get_failover_config(), now));
+    let mut served = None;
+    for id in candidates {
+        let circuit_breaker_epoch = match (id == primary, is_forced) {
+            (false, _) => None,
+            (true, true) => Some(CIRCUIT_BREAKER.with(|b| b.borrow().epoch)),
+            (true, false) => match acquire_circuit_breaker() {
+                Some(epoch) => Some(epoch),
+                None => continue,
+            },
+        };
+        let result = call_index(id, config.method.as_str(), config.args.clone()).await;
+        if let Some(epoch) = circuit_breaker_epoch {
+            record_circuit_breaker_outcome(epoch, result.as_ref().err().map(|(code, _)| *code));
+        }
+        let now = ic_cdk::api::time() / (1000 * 1000000);
+        FAILOVER.with(|f| match &result {
+            Ok(_) => f.borrow_mut().record_success(id),
+            Err(_) => f.borrow_mut().record_failure(id, now),
+        });
+        let is_ok = result.is_ok();
+        served = Some((id, result));
+        if is_ok {
+            break;
+        }
+    }
+    let Some((id, result)) = served else {
+        return record_skipped_execution(circuit_breaker_open_error().1);
+    };
+
+    if let Ok((Some(payload),)) = &result {
+        set_last_indexed_payload_hash(certification::payload_hash(payload));
+    }
     if result.is_ok() {
-        update_last_execution_result(None);
+        // NOTE: Cached responses may be stale once new data is indexed
+        clear_response_cache();
+        update_last_execution_result(None, id)
     } else {
-        update_last_execution_result(Some(Error {
-            message: format!("{:?}", result),
-        }));
+        update_last_execution_result(
+            Some(Error {
+                message: format!("{:?}", result),
+            }),
+            id,
+        )
+    }
+}
+
+async fn call_index(
+    target: Principal,
+    method: &str,
+    args: Vec<u8>,
+) -> CallResult<(Option<Vec<u8>>,)> {
+    ic_cdk::api::call::call(target, method, (args,)).await
+}
+
+/// Run the indexing task once, without waiting for the timer, and return the result of the run
+/// NOTE: `next_schedule` is not changed, the run is recorded as scheduled ones but not skipped by blackout windows or the funding guard
+#[update]
+#[candid_method(update)]
+async fn trigger_index_now() -> ExecutionResult {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Not permitted");
     }
+    _trigger_index_now(run_index).await
 }
 
-fn update_last_execution_result(error: Option<Error>) {
+/// `run` is `run_index`, given to run the indexing off the replica in tests
+async fn _trigger_index_now<F: Future<Output = ExecutionResult>>(
+    run: impl FnOnce(IndexingConfig, bool) -> F,
+) -> ExecutionResult {
+    run(configured_indexing_config(), true).await
+}
+
+fn configured_indexing_config() -> IndexingConfig {
+    let indexing_config = get_indexing_config();
+    assert!(
+        indexing_config.task_interval_secs > 0,
+        "indexing_config is not yet set"
+    );
+    indexing_config
+}
+
+/// Call the indexing method of the target and return its raw result
+/// NOTE: Nothing is recorded, intended to be used to verify the method and args before starting
+#[update]
+#[candid_method(update)]
+async fn dry_run_index(method: String, args: Vec<u8>) -> CallResult<(Option<Vec<u8>>,)> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Not permitted");
+    }
+    _dry_run_index(_target(), &method, args, call_index).await
+}
+
+/// `call` is `call_index`, given to run off the replica in tests
+async fn _dry_run_index<'a, F: Future<Output = CallResult<(Option<Vec<u8>>,)>>>(
+    target: Principal,
+    method: &'a str,
+    args: Vec<u8>,
+    call: impl FnOnce(Principal, &'a str, Vec<u8>) -> F,
+) -> CallResult<(Option<Vec<u8>>,)> {
+    call(target, method, args).await
+}
+
+fn update_last_execution_result(error: Option<Error>, served_by: Principal) -> ExecutionResult {
     let current_time_sec = (ic_cdk::api::time() / (1000 * 1000000)) as u64;
     if error.is_none() {
         set_last_succeeded(current_time_sec);
     }
-    set_last_execution_result(ExecutionResult {
+    let result = execution_result(error, served_by, current_time_sec);
+    set_last_execution_result(result.clone());
+    certify_execution_state();
+    result
+}
+
+fn execution_result(error: Option<Error>, served_by: Principal, timestamp: u64) -> ExecutionResult {
+    ExecutionResult {
         is_succeeded: error.is_none(),
-        timestamp: current_time_sec,
+        timestamp,
         error,
-    });
+        skip_reason: None,
+        served_by: Some(served_by),
+    }
+}
+
+fn record_skipped_execution(reason: String) -> ExecutionResult {
+    let current_time_sec = (ic_cdk::api::time() / (1000 * 1000000)) as u64;
+    let result = ExecutionResult {
+        is_succeeded: false,
+        timestamp: current_time_sec,
+        error: None,
+        skip_reason: Some(reason),
+        served_by: None,
+    };
+    set_last_execution_result(result.clone());
+    certify_execution_state();
+    result
+}
+
+#[query]
+#[candid_method(query)]
+fn get_failover_config() -> FailoverConfig {
+    FAILOVER_CONFIG.with(|f| f.borrow().get().clone())
+}
+
+#[update]
+#[candid_method(update)]
+fn set_failover_config(config: FailoverConfig) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Not permitted");
+    }
+    let res = FAILOVER_CONFIG.with(|f| f.borrow_mut().set(config));
+    res.unwrap();
+}
+
+/// Targets which failed in their latest runs
+/// NOTE: The health is on the heap, so every target is tried in order again after upgrades
+#[query]
+#[candid_method(query)]
+fn failover_health() -> Vec<(Principal, TargetHealth)> {
+    FAILOVER.with(|f| f.borrow().health())
 }
 
 #[update]
@@ -487,7 +1756,20 @@ async fn restart_indexing() {
 
 #[post_upgrade]
 fn post_upgrade() {
-    let config = get_indexing_config();
+    // NOTE: Validate all of the args before applying any of them, a trap here rolls back the upgrade
+    let args = decode_upgrade_args(&ic_cdk::api::call::arg_data_raw())
+        .unwrap_or_else(|e| ic_cdk::trap(&e));
+    let mut config = get_indexing_config();
+    if let Some(args) = args {
+        if let Some(scheduler) = &args.scheduler {
+            config =
+                override_indexing_config(config, scheduler).unwrap_or_else(|e| ic_cdk::trap(&e));
+        }
+        apply_upgrade_args(&args, config.clone());
+    }
+    // NOTE: The tree is on the heap, so it is rebuilt from the stable memory
+    certify_execution_state();
+
     if config.task_interval_secs > 0 {
         // If the timer was already started, set the timer again at the time of upgrade.
         start_indexing_internal(IndexingConfig {
@@ -497,6 +1779,57 @@ fn post_upgrade() {
     }
 }
 
+fn decode_upgrade_args(bytes: &[u8]) -> Result<Option<UpgradeArgs>, String> {
+    // NOTE: Older initializers install upgrades with an empty arg
+    if bytes.is_empty() {
+        return Ok(None);
+    }
+    Decode!(bytes, Option<UpgradeArgs>).map_err(|e| format!("Invalid upgrade args: {:?}", e))
+}
+
+fn override_indexing_config(
+    config: IndexingConfig,
+    overrides: &SchedulerOverrides,
+) -> Result<IndexingConfig, String> {
+    if config.task_interval_secs == 0 {
+        return Err("Scheduler overrides require indexing to be started".to_string());
+    }
+    if overrides.task_interval_secs == Some(0) {
+        return Err("task_interval_secs must be greater than 0".to_string());
+    }
+    Ok(IndexingConfig {
+        task_interval_secs: overrides
+            .task_interval_secs
+            .unwrap_or(config.task_interval_secs),
+        delay_secs: overrides.delay_secs.or(config.delay_secs),
+        is_rounded_start_time: overrides
+            .is_rounded_start_time
+            .or(config.is_rounded_start_time),
+        ..config
+    })
+}
+
+fn apply_upgrade_args(args: &UpgradeArgs, config: IndexingConfig) {
+    if let Some(registry) = args.registry {
+        set_registry(registry);
+    }
+    if let Some(vault) = args.vault {
+        _set_vault(vault);
+    }
+    if let Some(db) = args.db {
+        _set_db(db);
+    }
+    if args.scheduler.is_some() {
+        set_indexing_config(config);
+    }
+    if let Some(funding_guard) = &args.funding_guard {
+        _set_funding_guard_config(funding_guard.clone());
+    }
+    if let Some(circuit_breaker) = &args.circuit_breaker {
+        _set_circuit_breaker_config(circuit_breaker.clone());
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -535,4 +1868,336 @@ mod tests {
             50 * 60 + 30
         );
     }
+
+    #[test]
+    fn test_decode_upgrade_args() {
+        assert!(decode_upgrade_args(&[]).unwrap().is_none());
+        assert!(decode_upgrade_args(&Encode!().unwrap()).unwrap().is_none());
+        assert!(decode_upgrade_args(&Encode!(&None::<UpgradeArgs>).unwrap())
+            .unwrap()
+            .is_none());
+
+        let vault = Principal::from_text("ua42s-gaaaa-aaaal-achcq-cai").unwrap();
+        let args = UpgradeArgs {
+            vault: Some(vault),
+            ..Default::default()
+        };
+        let decoded = decode_upgrade_args(&Encode!(&Some(args)).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(decoded.vault, Some(vault));
+        assert!(decoded.registry.is_none());
+
+        assert!(decode_upgrade_args(&[0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_override_indexing_config() {
+        let config = IndexingConfig {
+            task_interval_secs: 60,
+            method: "index".to_string(),
+            args: vec![1, 2, 3],
+            delay_secs: Some(10),
+            is_rounded_start_time: Some(false),
+            ..Default::default()
+        };
+        let overridden = override_indexing_config(
+            config.clone(),
+            &SchedulerOverrides {
+                task_interval_secs: Some(3600),
+                is_rounded_start_time: Some(true),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(overridden.task_interval_secs, 3600);
+        assert_eq!(overridden.delay_secs, Some(10));
+        assert_eq!(overridden.is_rounded_start_time, Some(true));
+        assert_eq!(overridden.method, config.method);
+        assert_eq!(overridden.args, config.args);
+
+        assert!(override_indexing_config(
+            config,
+            &SchedulerOverrides {
+                task_interval_secs: Some(0),
+                ..Default::default()
+            },
+        )
+        .is_err());
+        assert!(override_indexing_config(
+            IndexingConfig::default(),
+            &SchedulerOverrides::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "indexing_config is not yet set")]
+    fn test_trigger_index_now_without_config() {
+        configured_indexing_config();
+    }
+
+    #[test]
+    fn test_trigger_index_now() {
+        set_indexing_config(IndexingConfig {
+            task_interval_secs: 60,
+            method: "index".to_string(),
+            args: vec![1],
+            ..Default::default()
+        });
+        set_next_schedule(1_000);
+        let run = ExecutionResult {
+            is_succeeded: true,
+            timestamp: 10,
+            error: None,
+            skip_reason: None,
+            served_by: None,
+        };
+        let result = futures::executor::block_on(_trigger_index_now(|config, is_forced| {
+            assert_eq!(config.method, "index");
+            assert!(is_forced);
+            std::future::ready(run.clone())
+        }));
+        // the result of this run, not the last one recorded
+        assert_eq!(result, run);
+        assert_ne!(last_execution_result(), run);
+        assert_eq!(next_schedule(), 1_000);
+    }
+
+    #[test]
+    fn test_dry_run_index() {
+        let target = Principal::from_text("ua42s-gaaaa-aaaal-achcq-cai").unwrap();
+        let last = last_execution_result();
+        let result = futures::executor::block_on(_dry_run_index(
+            target,
+            "index",
+            vec![1],
+            |id, method, args| {
+                assert_eq!((id, method, args), (target, "index", vec![1]));
+                std::future::ready(Ok((Some(vec![2]),)))
+            },
+        ));
+        assert_eq!(result, Ok((Some(vec![2]),)));
+        assert_eq!(last_execution_result(), last);
+        assert_eq!(last_indexed_payload_hash(), None);
+
+        let result =
+            futures::executor::block_on(_dry_run_index(target, "index", vec![], |_, _, _| {
+                std::future::ready(Err((RejectionCode::CanisterError, "trapped".to_string())))
+            }));
+        assert_eq!(
+            result,
+            Err((RejectionCode::CanisterError, "trapped".to_string()))
+        );
+        assert_eq!(last_execution_result(), last);
+    }
+
+    #[test]
+    fn test_indexing_config_without_blackout() {
+        #[derive(candid::CandidType)]
+        struct PrevIndexingConfig {
+            task_interval_secs: u32,
+            method: String,
+            args: Vec<u8>,
+            delay_secs: Option<u32>,
+            is_rounded_start_time: Option<bool>,
+        }
+        let bytes = Encode!(&PrevIndexingConfig {
+            task_interval_secs: 60,
+            method: "index".to_string(),
+            args: vec![],
+            delay_secs: None,
+            is_rounded_start_time: None,
+        })
+        .unwrap();
+        let config =
+            <IndexingConfig as ic_stable_structures::Storable>::from_bytes(Cow::Owned(bytes));
+        assert_eq!(config.task_interval_secs, 60);
+        assert!(config.blackout_windows.is_none());
+        assert!(config.blackout_policy.is_none());
+    }
+
+    #[test]
+    fn test_run_records() {
+        let standby = Principal::from_text("uh54g-lyaaa-aaaal-achca-cai").unwrap();
+        let day = 24 * 60 * 60;
+        let now = 100 * day;
+        for i in 0..3 {
+            put_run_record(RunRecord {
+                at: now - 31 * day + i,
+                outcome: RunOutcome::Succeeded,
+                latency_ms: Some(10),
+                served_by: None,
+            });
+        }
+        put_run_record(RunRecord {
+            at: now,
+            outcome: RunOutcome::Failed,
+            latency_ms: Some(20),
+            served_by: Some(standby),
+        });
+        // records out of the longest window are removed
+        assert_eq!(RUN_RECORDS.with(|m| m.borrow().len()), 1);
+        assert_eq!(run_records(10)[0].served_by, Some(standby));
+
+        let report = _sla_report(now);
+        assert_eq!(report.windows[0].failed, 1);
+        assert_eq!(report.windows[0].latency_p50_ms, Some(20));
+
+        for _ in 0..sla::MAX_RECORDS {
+            put_run_record(RunRecord {
+                at: now,
+                outcome: RunOutcome::Skipped,
+                latency_ms: None,
+                served_by: None,
+            });
+        }
+        assert_eq!(RUN_RECORDS.with(|m| m.borrow().len()), sla::MAX_RECORDS);
+        assert_eq!(_sla_report(now).windows[0].failed, 0);
+    }
+
+    #[test]
+    fn test_check_payment() {
+        assert_eq!(check_payment(100, 0), Ok(()));
+        assert_eq!(check_payment(100, 100), Ok(()));
+        let err = check_payment(99, 100).unwrap_err();
+        assert_eq!(err.0, RejectionCode::SysTransient);
+    }
+
+    #[test]
+    fn test_proxy_call_logs() {
+        let caller = Principal::from_text("ua42s-gaaaa-aaaal-achcq-cai").unwrap();
+        let log = |at: u64| ProxyCallLog {
+            caller,
+            method: "method".to_string(),
+            at,
+            is_succeeded: true,
+            cycles_forwarded: 100,
+            cycles_refunded: 30,
+            rejection: None,
+        };
+        assert!(proxy_call_logs(10).is_empty());
+
+        for at in 0..(MAX_PROXY_CALL_LOGS + 5) {
+            put_proxy_call_log(log(at));
+        }
+        let logs = proxy_call_logs(3);
+        assert_eq!(
+            logs,
+            vec![
+                log(MAX_PROXY_CALL_LOGS + 4),
+                log(MAX_PROXY_CALL_LOGS + 3),
+                log(MAX_PROXY_CALL_LOGS + 2)
+            ]
+        );
+        let logs = proxy_call_logs(MAX_PROXY_CALL_LOGS * 2);
+        assert_eq!(logs.len() as u64, MAX_PROXY_CALL_LOGS);
+        assert_eq!(logs.last(), Some(&log(5)));
+
+        put_proxy_call_log(ProxyCallLog {
+            method: "m".repeat(1000),
+            ..log(0)
+        });
+        assert_eq!(proxy_call_logs(1)[0].method.len(), MAX_LOGGED_METHOD_LEN);
+    }
+
+    #[test]
+    fn test_method_configs() {
+        assert_eq!(method_config_of("get").kind, MethodKind::Update);
+
+        _set_method_config(
+            "get".to_string(),
+            Some(MethodConfig {
+                kind: MethodKind::Query,
+                cache_ttl_secs: None,
+            }),
+        );
+        assert_eq!(method_config_of("get").kind, MethodKind::Query);
+        assert_eq!(method_config_of("put").kind, MethodKind::Update);
+        assert_eq!(get_method_configs().len(), 1);
+
+        _set_method_config("get".to_string(), None);
+        assert_eq!(method_config_of("get").kind, MethodKind::Update);
+        assert!(get_method_configs().is_empty());
+    }
+
+    #[test]
+    fn test_idempotent_call() {
+        let caller = Principal::from_text("ua42s-gaaaa-aaaal-achcq-cai").unwrap();
+        let key = |key: &str| IdempotencyKey {
+            caller,
+            key: key.to_string(),
+        };
+
+        // first call is forwarded, duplicates in flight are rejected
+        assert!(begin_idempotent_call(&key("a"), 0).is_none());
+        assert_eq!(
+            begin_idempotent_call(&key("a"), 1).unwrap().unwrap_err().0,
+            RejectionCode::CanisterReject
+        );
+        finish_idempotent_call(&key("a"), Some(IdempotentOutcome::Replied(vec![1])));
+        assert_eq!(begin_idempotent_call(&key("a"), 2), Some(Ok((vec![1],))));
+
+        // keys are scoped by callers
+        let other = IdempotencyKey {
+            caller: Principal::anonymous(),
+            key: "a".to_string(),
+        };
+        assert!(begin_idempotent_call(&other, 2).is_none());
+
+        // failed calls can be retried
+        assert!(begin_idempotent_call(&key("b"), 0).is_none());
+        finish_idempotent_call(&key("b"), None);
+        assert!(begin_idempotent_call(&key("b"), 1).is_none());
+
+        // expired
+        assert!(begin_idempotent_call(&key("a"), idempotency::TTL_SECS).is_none());
+
+        assert!(
+            begin_idempotent_call(&key(&"k".repeat(idempotency::MAX_KEY_LEN + 1)), 0)
+                .unwrap()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_idempotent_entries_bounded() {
+        let caller = Principal::from_text("ua42s-gaaaa-aaaal-achcq-cai").unwrap();
+        let key = |i: u64| IdempotencyKey {
+            caller,
+            key: i.to_string(),
+        };
+        for i in 0..(idempotency::MAX_ENTRIES + 2) {
+            assert!(begin_idempotent_call(&key(i), 0).is_none());
+        }
+        assert_eq!(
+            IDEMPOTENT_ENTRIES.with(|m| m.borrow().len()),
+            idempotency::MAX_ENTRIES
+        );
+        assert_eq!(
+            IDEMPOTENT_ENTRY_ORDER.with(|m| m.borrow().len()),
+            idempotency::MAX_ENTRIES
+        );
+        // the oldest ones are evicted
+        assert!(begin_idempotent_call(&key(0), 1).is_none());
+        assert!(begin_idempotent_call(&key(idempotency::MAX_ENTRIES + 1), 1).is_some());
+    }
+
+    #[test]
+    fn test_funding_skip_reason() {
+        let guard = FundingGuardConfig {
+            proxy_min_cycles: Some(1_000),
+            target_min_cycles: Some(5_000),
+        };
+        assert!(funding_skip_reason(&guard, 1_000, Some(5_000)).is_none());
+        assert!(funding_skip_reason(&guard, 999, Some(5_000))
+            .unwrap()
+            .starts_with("proxy is underfunded"));
+        assert!(funding_skip_reason(&guard, 1_000, Some(4_999))
+            .unwrap()
+            .starts_with("target is underfunded"));
+        // unknown balance of the target does not block indexing
+        assert!(funding_skip_reason(&guard, 1_000, None).is_none());
+        assert!(funding_skip_reason(&FundingGuardConfig::default(), 0, Some(0)).is_none());
+    }
 }
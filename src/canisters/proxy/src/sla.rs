@@ -0,0 +1,196 @@
+//! Rolling statistics of the scheduled indexing runs reported to the customers
+use std::borrow::Cow;
+
+use candid::{Decode, Encode, Principal};
+
+pub const WINDOWS_SECS: [u64; 4] = [60 * 60, 24 * 60 * 60, 7 * 24 * 60 * 60, 30 * 24 * 60 * 60];
+pub const RETENTION_SECS: u64 = 30 * 24 * 60 * 60;
+/// NOTE: Runs more frequent than every ~4 minutes are not fully covered by the longest window
+pub const MAX_RECORDS: u64 = 10_000;
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub enum RunOutcome {
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+#[derive(
+    Clone, Debug, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct RunRecord {
+    pub at: u64,
+    pub outcome: RunOutcome,
+    /// None for skipped runs
+    pub latency_ms: Option<u64>,
+    /// None for skipped runs
+    pub served_by: Option<Principal>,
+}
+impl ic_stable_structures::Storable for RunRecord {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl ic_stable_structures::BoundedStorable for RunRecord {
+    const MAX_SIZE: u32 = 256;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct SlaWindow {
+    pub window_secs: u64,
+    pub scheduled: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    pub skipped: u64,
+    pub latency_p50_ms: Option<u64>,
+    pub latency_p90_ms: Option<u64>,
+    pub latency_p99_ms: Option<u64>,
+}
+
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct SlaReport {
+    pub generated_at: u64,
+    pub windows: Vec<SlaWindow>,
+}
+
+/// Aggregate the records newer than each window
+pub fn report(records: impl Iterator<Item = RunRecord>, now: u64) -> SlaReport {
+    let mut windows: Vec<SlaWindow> = WINDOWS_SECS
+        .iter()
+        .map(|secs| SlaWindow {
+            window_secs: *secs,
+            ..Default::default()
+        })
+        .collect();
+    let mut latencies: Vec<Vec<u64>> = vec![vec![]; WINDOWS_SECS.len()];
+    for record in records {
+        for (window, latencies) in windows.iter_mut().zip(latencies.iter_mut()) {
+            if now.saturating_sub(record.at) >= window.window_secs {
+                continue;
+            }
+            window.scheduled += 1;
+            match record.outcome {
+                RunOutcome::Succeeded => window.succeeded += 1,
+                RunOutcome::Failed => window.failed += 1,
+                RunOutcome::Skipped => window.skipped += 1,
+            }
+            if let Some(latency) = record.latency_ms {
+                latencies.push(latency);
+            }
+        }
+    }
+    for (window, mut latencies) in windows.iter_mut().zip(latencies) {
+        latencies.sort_unstable();
+        window.latency_p50_ms = percentile(&latencies, 50);
+        window.latency_p90_ms = percentile(&latencies, 90);
+        window.latency_p99_ms = percentile(&latencies, 99);
+    }
+    SlaReport {
+        generated_at: now,
+        windows,
+    }
+}
+
+// nearest-rank
+fn percentile(sorted: &[u64], p: usize) -> Option<u64> {
+    if sorted.is_empty() {
+        return None;
+    }
+    let rank = (p * sorted.len()).div_ceil(100).max(1);
+    Some(sorted[rank - 1])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 60 * 60;
+    const DAY: u64 = 24 * HOUR;
+
+    fn record(at: u64, outcome: RunOutcome, latency_ms: Option<u64>) -> RunRecord {
+        RunRecord {
+            at,
+            outcome,
+            latency_ms,
+            served_by: None,
+        }
+    }
+
+    #[test]
+    fn test_report() {
+        let now = 40 * DAY;
+        let records = vec![
+            record(now - 10, RunOutcome::Succeeded, Some(100)),
+            record(now - HOUR / 2, RunOutcome::Skipped, None),
+            record(now - 2 * HOUR, RunOutcome::Failed, Some(300)),
+            record(now - 3 * DAY, RunOutcome::Succeeded, Some(200)),
+            record(now - 20 * DAY, RunOutcome::Succeeded, Some(400)),
+            record(now - 31 * DAY, RunOutcome::Succeeded, Some(500)),
+        ];
+        let report = report(records.into_iter(), now);
+        assert_eq!(report.generated_at, now);
+        assert_eq!(
+            report.windows[0],
+            SlaWindow {
+                window_secs: HOUR,
+                scheduled: 2,
+                succeeded: 1,
+                failed: 0,
+                skipped: 1,
+                latency_p50_ms: Some(100),
+                latency_p90_ms: Some(100),
+                latency_p99_ms: Some(100),
+            }
+        );
+        assert_eq!(report.windows[1].scheduled, 3);
+        assert_eq!(report.windows[1].failed, 1);
+        assert_eq!(report.windows[2].scheduled, 4);
+        assert_eq!(report.windows[2].latency_p50_ms, Some(200));
+        assert_eq!(report.windows[3].scheduled, 5);
+        assert_eq!(report.windows[3].succeeded, 3);
+        assert_eq!(report.windows[3].latency_p99_ms, Some(400));
+    }
+
+    #[test]
+    fn test_report_empty() {
+        let report = report(std::iter::empty(), 100);
+        assert_eq!(report.windows.len(), WINDOWS_SECS.len());
+        assert!(report
+            .windows
+            .iter()
+            .all(|w| w.scheduled == 0 && w.latency_p50_ms.is_none()));
+    }
+
+    #[test]
+    fn test_max_size() {
+        use ic_stable_structures::{BoundedStorable, Storable};
+        let record = RunRecord {
+            at: u64::MAX,
+            outcome: RunOutcome::Succeeded,
+            latency_ms: Some(u64::MAX),
+            served_by: Some(Principal::from_slice(&[0xff; 29])),
+        };
+        assert!(record.to_bytes().len() as u32 <= RunRecord::MAX_SIZE);
+    }
+
+    #[test]
+    fn test_percentile() {
+        let sorted: Vec<u64> = (1..=100).collect();
+        assert_eq!(percentile(&sorted, 50), Some(50));
+        assert_eq!(percentile(&sorted, 90), Some(90));
+        assert_eq!(percentile(&sorted, 99), Some(99));
+        assert_eq!(percentile(&[7], 99), Some(7));
+        assert_eq!(percentile(&[1, 2, 3], 50), Some(2));
+        assert_eq!(percentile(&[], 50), None);
+    }
+}
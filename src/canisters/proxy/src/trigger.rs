@@ -0,0 +1,193 @@
+//! Indexing triggered by notifications of updates from upstream canisters
+//!
+//! - Runs are started at least `min_gap_secs` apart, counted from the start of the last run
+//! - Notifications while a run is scheduled are coalesced into it
+//! - Notifications and timer runs while a run is in flight are coalesced into a single run after it finishes
+use std::borrow::Cow;
+
+use candid::{Decode, Encode, Principal};
+
+/// NOTE: Runs never finished (e.g. trapped in a callback) do not block the trigger after this
+pub const IN_FLIGHT_TIMEOUT_SECS: u64 = 5 * 60;
+
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct EventTriggerConfig {
+    /// Callers permitted to call `notify_update` in addition to the target and controllers
+    pub notifiers: Vec<Principal>,
+    pub min_gap_secs: u64,
+    /// Only notifications trigger the indexing if true
+    pub is_timer_disabled: bool,
+}
+impl ic_stable_structures::Storable for EventTriggerConfig {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+
+#[derive(
+    Clone, Debug, Default, PartialEq, Eq, candid::CandidType, candid::Deserialize, serde::Serialize,
+)]
+pub struct EventTrigger {
+    pub in_flight_since: Option<u64>,
+    pub last_started_at: Option<u64>,
+    pub scheduled_at: Option<u64>,
+    pub is_pending: bool,
+}
+
+impl EventTrigger {
+    /// Returns the time to run at if a new run must be scheduled
+    pub fn notify(&mut self, min_gap_secs: u64, now: u64) -> Option<u64> {
+        if self.scheduled_at.is_some() {
+            return None;
+        }
+        if self.is_in_flight(now) {
+            self.is_pending = true;
+            return None;
+        }
+        let run_at = match self.last_started_at {
+            Some(started_at) => now.max(started_at.saturating_add(min_gap_secs)),
+            None => now,
+        };
+        self.scheduled_at = Some(run_at);
+        Some(run_at)
+    }
+
+    /// Called on the start of any run, scheduled ones are consumed by it
+    /// Returns false if a run is in flight, then the run is coalesced into a single run after it
+    pub fn start(&mut self, now: u64) -> bool {
+        if self.scheduled_at.is_some_and(|at| at <= now) {
+            self.scheduled_at = None;
+        }
+        if self.is_in_flight(now) {
+            self.is_pending = true;
+            return false;
+        }
+        self.in_flight_since = Some(now);
+        self.last_started_at = Some(now);
+        true
+    }
+
+    /// Returns the time to run at if a run was requested during the run started at `started_at`
+    /// NOTE: A stale run finishing after another run started does not finish the other
+    pub fn finish(&mut self, started_at: u64, min_gap_secs: u64, now: u64) -> Option<u64> {
+        if self.in_flight_since != Some(started_at) {
+            return None;
+        }
+        self.in_flight_since = None;
+        if !self.is_pending {
+            return None;
+        }
+        self.is_pending = false;
+        self.notify(min_gap_secs, now)
+    }
+
+    fn is_in_flight(&self, now: u64) -> bool {
+        match self.in_flight_since {
+            Some(since) => now < since.saturating_add(IN_FLIGHT_TIMEOUT_SECS),
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_debounce() {
+        let mut trigger = EventTrigger::default();
+        assert_eq!(trigger.notify(60, 100), Some(100));
+        // coalesced into the scheduled run
+        assert_eq!(trigger.notify(60, 100), None);
+        assert!(trigger.start(100));
+        assert_eq!(trigger.finish(100, 60, 110), None);
+
+        assert_eq!(trigger.notify(60, 120), Some(160));
+        assert_eq!(trigger.notify(60, 130), None);
+        assert!(trigger.start(160));
+        trigger.finish(160, 60, 161);
+        assert_eq!(trigger.notify(60, 300), Some(300));
+    }
+
+    #[test]
+    fn test_in_flight() {
+        let mut trigger = EventTrigger::default();
+        assert!(trigger.start(100)); // e.g. by the timer
+        assert_eq!(trigger.notify(60, 110), None);
+        assert_eq!(trigger.notify(60, 120), None);
+        assert!(trigger.is_pending);
+        // a single run follows the in-flight one
+        assert_eq!(trigger.finish(100, 60, 130), Some(160));
+        assert_eq!(trigger.notify(60, 140), None);
+        assert!(!trigger.is_pending);
+    }
+
+    #[test]
+    fn test_stale_in_flight() {
+        let mut trigger = EventTrigger::default();
+        assert!(trigger.start(100));
+        assert_eq!(trigger.notify(0, 100 + IN_FLIGHT_TIMEOUT_SECS - 1), None);
+        trigger.is_pending = false;
+        assert_eq!(
+            trigger.notify(0, 100 + IN_FLIGHT_TIMEOUT_SECS),
+            Some(100 + IN_FLIGHT_TIMEOUT_SECS)
+        );
+    }
+
+    #[test]
+    fn test_timer_run_does_not_consume_future_schedule() {
+        let mut trigger = EventTrigger::default();
+        assert!(trigger.start(100));
+        trigger.finish(100, 60, 101);
+        assert_eq!(trigger.notify(60, 110), Some(160));
+        assert!(trigger.start(150)); // by the timer
+        trigger.finish(150, 60, 151);
+        assert_eq!(trigger.scheduled_at, Some(160));
+    }
+
+    #[test]
+    fn test_notified_run_during_timer_run() {
+        let mut trigger = EventTrigger::default();
+        assert_eq!(trigger.notify(0, 90), Some(90));
+        // the timer starts a run before the notified timer fires
+        assert!(trigger.start(85));
+        assert_eq!(trigger.scheduled_at, Some(90));
+        // the notified run is coalesced instead of calling the target concurrently
+        assert!(!trigger.start(90));
+        assert_eq!(trigger.in_flight_since, Some(85));
+        assert_eq!(trigger.scheduled_at, None);
+        assert!(trigger.is_pending);
+        assert_eq!(trigger.finish(85, 0, 95), Some(95));
+        assert!(trigger.start(95));
+        assert_eq!(trigger.finish(95, 0, 96), None);
+    }
+
+    #[test]
+    fn test_timer_run_during_notified_run() {
+        let mut trigger = EventTrigger::default();
+        assert_eq!(trigger.notify(60, 100), Some(100));
+        assert!(trigger.start(100));
+        // a timer tick while the notified run is in flight
+        assert!(!trigger.start(110));
+        assert_eq!(trigger.last_started_at, Some(100));
+        assert_eq!(trigger.finish(100, 60, 120), Some(160));
+        assert_eq!(trigger.in_flight_since, None);
+    }
+
+    #[test]
+    fn test_stale_run_does_not_finish_next_run() {
+        let mut trigger = EventTrigger::default();
+        assert!(trigger.start(100));
+        let next = 100 + IN_FLIGHT_TIMEOUT_SECS;
+        assert!(trigger.start(next));
+        // the stale run finishes while the next one is in flight
+        assert_eq!(trigger.finish(100, 0, next + 1), None);
+        assert_eq!(trigger.in_flight_since, Some(next));
+        assert!(!trigger.start(next + 2));
+    }
+}
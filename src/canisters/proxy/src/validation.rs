@@ -0,0 +1,98 @@
+//! Validation of the indexing method and args given by the target
+use candid::{
+    check_prog,
+    types::{Type, TypeInner},
+    IDLArgs, IDLProg, TypeEnv,
+};
+
+/// `args` is passed through to the target, so it must be well-formed candid
+/// NOTE: Empty args are allowed for methods which ignore them
+pub fn validate_args(args: &[u8]) -> Result<(), String> {
+    if args.is_empty() {
+        return Ok(());
+    }
+    IDLArgs::from_bytes(args)
+        .map(|_| ())
+        .map_err(|e| format!("args is not well-formed candid: {}", e))
+}
+
+/// Check that `method` exists in the candid interface and accepts `(vec nat8)`, as it is called by `index()`
+pub fn validate_method(did: &str, method: &str) -> Result<(), String> {
+    let prog: IDLProg = did
+        .parse()
+        .map_err(|e| format!("Failed to parse the candid interface of the target: {}", e))?;
+    let mut env = TypeEnv::new();
+    let actor = check_prog(&mut env, &prog)
+        .map_err(|e| format!("Invalid candid interface of the target: {}", e))?
+        .ok_or_else(|| "The candid interface of the target has no service".to_string())?;
+    let func = env
+        .get_method(&actor, method)
+        .map_err(|_| format!("Method not found in the target: {}", method))?;
+
+    let compatible = match func.args.split_first() {
+        Some((first, rest)) => is_blob(&env, first) && rest.iter().all(|t| is_optional(&env, t)),
+        None => false,
+    };
+    if !compatible {
+        return Err(format!(
+            "Incompatible signature of {}: expected (vec nat8), found {}",
+            method, func
+        ));
+    }
+    Ok(())
+}
+
+fn is_blob(env: &TypeEnv, ty: &Type) -> bool {
+    match env.trace_type(ty).map(|t| t.as_ref().clone()) {
+        Ok(TypeInner::Vec(inner)) => matches!(
+            env.trace_type(&inner).map(|t| t.as_ref().clone()),
+            Ok(TypeInner::Nat8)
+        ),
+        _ => false,
+    }
+}
+
+fn is_optional(env: &TypeEnv, ty: &Type) -> bool {
+    matches!(
+        env.trace_type(ty).map(|t| t.as_ref().clone()),
+        Ok(TypeInner::Opt(_) | TypeInner::Null | TypeInner::Reserved)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Encode;
+
+    use super::*;
+
+    const DID: &str = r#"
+type Args = vec nat8;
+service : (principal) -> {
+  index : (vec nat8) -> (opt vec nat8);
+  index_with_opt : (blob, opt text) -> ();
+  index_with_alias : (Args) -> ();
+  get : (text) -> (text) query;
+}
+"#;
+
+    #[test]
+    fn test_validate_args() {
+        assert!(validate_args(&[]).is_ok());
+        assert!(validate_args(&Encode!(&1u64, &"a").unwrap()).is_ok());
+        assert!(validate_args(&[0, 1, 2]).is_err());
+    }
+
+    #[test]
+    fn test_validate_method() {
+        assert!(validate_method(DID, "index").is_ok());
+        assert!(validate_method(DID, "index_with_opt").is_ok());
+        assert!(validate_method(DID, "index_with_alias").is_ok());
+        assert!(validate_method(DID, "indx")
+            .unwrap_err()
+            .starts_with("Method not found"));
+        assert!(validate_method(DID, "get")
+            .unwrap_err()
+            .starts_with("Incompatible signature"));
+        assert!(validate_method("service :", "index").is_err());
+    }
+}
@@ -0,0 +1,155 @@
+//! Deposits of ICP converted to cycles by the cycles minting canister (CMC)
+//!
+//! - A depositor transfers ICP to its deposit account, a subaccount of the vault on the ICP ledger
+//! - `notify_icp_deposit` transfers the balance of the account to the CMC and notifies it to top up the vault
+//! - The minted cycles are credited to the depositor as shares, as `supply` does
+//! - Deposits are identified by the depositor and the block of the transfer to the CMC made by the vault
+use async_trait::async_trait;
+use candid::{CandidType, Nat, Principal};
+use ic_cdk::api::call::CallResult;
+use serde::Deserialize;
+
+use crate::icrc::{Account, TransferArg, TransferError};
+
+pub const ICP_LEDGER_CANISTER_ID: &str = "ryjl3-tyaaa-aaaaa-aaaba-cai";
+pub const CMC_CANISTER_ID: &str = "rkp4c-7iaaa-aaaaa-aaaca-cai";
+pub const ICP_FEE_E8S: u64 = 10_000;
+/// A notification in flight for longer, e.g. as its callback trapped, can be notified again
+pub const NOTIFY_IN_FLIGHT_TIMEOUT_SECS: u64 = 5 * 60;
+/// NOTE: The CMC tops up the canister of the subaccount for transfers with this memo
+const MEMO_TOP_UP_CANISTER: u64 = 0x50555054; // "TPUP"
+
+// Subset of the bindings in initializer/src/cmc/types.rs
+pub type BlockIndex = u64;
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct NotifyTopUpArg {
+    pub block_index: BlockIndex,
+    pub canister_id: Principal,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum NotifyError {
+    Refunded {
+        block_index: Option<BlockIndex>,
+        reason: String,
+    },
+    InvalidTransaction(String),
+    Other {
+        error_message: String,
+        error_code: u64,
+    },
+    Processing,
+    TransactionTooOld(BlockIndex),
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum NotifyTopUpResult {
+    Ok(Nat),
+    Err(NotifyError),
+}
+
+#[async_trait(?Send)]
+pub trait IcpLedger {
+    async fn icrc1_balance_of(&self, account: Account) -> CallResult<Nat>;
+    async fn icrc1_transfer(&self, arg: TransferArg) -> CallResult<Result<Nat, TransferError>>;
+}
+
+#[async_trait(?Send)]
+pub trait CyclesMinting {
+    async fn notify_top_up(&self, arg: NotifyTopUpArg) -> CallResult<NotifyTopUpResult>;
+}
+
+pub struct IcpLedgerProvider(pub Principal);
+
+#[async_trait(?Send)]
+impl IcpLedger for IcpLedgerProvider {
+    async fn icrc1_balance_of(&self, account: Account) -> CallResult<Nat> {
+        ic_cdk::call(self.0, "icrc1_balance_of", (account,))
+            .await
+            .map(|(balance,)| balance)
+    }
+    async fn icrc1_transfer(&self, arg: TransferArg) -> CallResult<Result<Nat, TransferError>> {
+        ic_cdk::call(self.0, "icrc1_transfer", (arg,))
+            .await
+            .map(|(res,)| res)
+    }
+}
+
+pub struct CmcProvider(pub Principal);
+
+#[async_trait(?Send)]
+impl CyclesMinting for CmcProvider {
+    async fn notify_top_up(&self, arg: NotifyTopUpArg) -> CallResult<NotifyTopUpResult> {
+        ic_cdk::call(self.0, "notify_top_up", (arg,))
+            .await
+            .map(|(res,)| res)
+    }
+}
+
+pub fn ledger() -> IcpLedgerProvider {
+    IcpLedgerProvider(Principal::from_text(ICP_LEDGER_CANISTER_ID).unwrap())
+}
+
+pub fn cmc() -> CmcProvider {
+    CmcProvider(Principal::from_text(CMC_CANISTER_ID).unwrap())
+}
+
+/// Subaccount of the principal in the conventional layout, the length followed by the bytes
+pub fn principal_to_subaccount(principal: Principal) -> Vec<u8> {
+    let bytes = principal.as_slice();
+    let mut subaccount = vec![0; 32];
+    subaccount[0] = bytes.len() as u8;
+    subaccount[1..1 + bytes.len()].copy_from_slice(bytes);
+    subaccount
+}
+
+pub fn deposit_account(vault: Principal, depositor: Principal) -> Account {
+    Account {
+        owner: vault,
+        subaccount: Some(principal_to_subaccount(depositor)),
+    }
+}
+
+/// Transfer of `amount_e8s` from the deposit account to the CMC to top up the vault
+pub fn top_up_transfer(vault: Principal, depositor: Principal, amount_e8s: u64) -> TransferArg {
+    TransferArg {
+        from_subaccount: Some(principal_to_subaccount(depositor)),
+        to: Account {
+            owner: Principal::from_text(CMC_CANISTER_ID).unwrap(),
+            subaccount: Some(principal_to_subaccount(vault)),
+        },
+        amount: Nat::from(amount_e8s),
+        fee: Some(Nat::from(ICP_FEE_E8S)),
+        memo: Some(MEMO_TOP_UP_CANISTER.to_le_bytes().to_vec()),
+        created_at_time: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_principal_to_subaccount() {
+        let principal = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let subaccount = principal_to_subaccount(principal);
+        assert_eq!(subaccount.len(), 32);
+        assert_eq!(subaccount[0], 10);
+        assert_eq!(&subaccount[1..11], principal.as_slice());
+        assert!(subaccount[11..].iter().all(|b| *b == 0));
+    }
+
+    #[test]
+    fn test_top_up_transfer() {
+        let vault = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let depositor = Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap();
+        let arg = top_up_transfer(vault, depositor, 100);
+        assert_eq!(
+            arg.from_subaccount,
+            deposit_account(vault, depositor).subaccount
+        );
+        assert_eq!(arg.to.subaccount, Some(principal_to_subaccount(vault)));
+        assert_eq!(arg.memo, Some(b"TPUP\0\0\0\0".to_vec()));
+    }
+}
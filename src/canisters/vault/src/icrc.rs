@@ -0,0 +1,358 @@
+//! ICRC-1 and ICRC-2 interfaces over the shares of the vault
+//!
+//! - The token is the `Index` share, so the total supply is the index and the balance is the share
+//! - Shares are minted by `supply` and burned by `withdraw`, not by transfers from or to the minting account
+//! - Only the default subaccount holds shares, as shares are kept per principal
+//! - Transfers are free, and those with `created_at_time` are deduplicated within the window
+//! - Approvals are logged apart from the transfers, so their indices are in their own sequence
+use candid::{CandidType, Encode, Int, Nat, Principal};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+pub const TOKEN_NAME: &str = "Vault Share";
+pub const TOKEN_SYMBOL: &str = "VSHARE";
+/// NOTE: Shares are initially issued one per cycle
+pub const TOKEN_DECIMALS: u8 = 12;
+pub const MAX_MEMO_LEN: usize = 32;
+const TX_WINDOW_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+const PERMITTED_DRIFT_NANOS: u64 = 60 * 1_000_000_000;
+
+pub const ERROR_CODE_UNSUPPORTED_SUBACCOUNT: u64 = 1;
+pub const ERROR_CODE_TOO_LONG_MEMO: u64 = 2;
+pub const ERROR_CODE_MINTING_ACCOUNT: u64 = 3;
+pub const ERROR_CODE_INVALID_TRANSFER: u64 = 4;
+pub const ERROR_CODE_SELF_APPROVAL: u64 = 5;
+
+pub type Subaccount = Vec<u8>;
+pub type TxHash = [u8; 32];
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Account {
+    pub owner: Principal,
+    pub subaccount: Option<Subaccount>,
+}
+impl Account {
+    pub fn is_default(&self) -> bool {
+        is_default_subaccount(&self.subaccount)
+    }
+}
+impl From<Principal> for Account {
+    fn from(owner: Principal) -> Self {
+        Self {
+            owner,
+            subaccount: None,
+        }
+    }
+}
+
+pub fn is_default_subaccount(subaccount: &Option<Subaccount>) -> bool {
+    match subaccount {
+        Some(s) => s.iter().all(|b| *b == 0),
+        None => true,
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum MetadataValue {
+    Nat(Nat),
+    Int(Int),
+    Text(String),
+    Blob(Vec<u8>),
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct StandardRecord {
+    pub name: String,
+    pub url: String,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TransferArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ApproveArgs {
+    pub from_subaccount: Option<Subaccount>,
+    pub spender: Account,
+    pub amount: Nat,
+    pub expected_allowance: Option<Nat>,
+    pub expires_at: Option<u64>,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum ApproveError {
+    BadFee { expected_fee: Nat },
+    InsufficientFunds { balance: Nat },
+    AllowanceChanged { current_allowance: Nat },
+    Expired { ledger_time: u64 },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct AllowanceArgs {
+    pub account: Account,
+    pub spender: Account,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct Allowance {
+    pub allowance: Nat,
+    pub expires_at: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct TransferFromArgs {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: Account,
+    pub to: Account,
+    pub amount: Nat,
+    pub fee: Option<Nat>,
+    pub memo: Option<Vec<u8>>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+/// Errors common to the update methods, converted into the error of each method
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum CommonError {
+    BadFee,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    GenericError { error_code: u64, message: String },
+}
+impl CommonError {
+    pub fn generic(error_code: u64, message: &str) -> Self {
+        Self::GenericError {
+            error_code,
+            message: message.to_string(),
+        }
+    }
+}
+/// The errors of the update methods share the variants of `CommonError`
+macro_rules! impl_from_common_error {
+    ($($error:ident),*) => {
+        $(
+            impl From<CommonError> for $error {
+                fn from(err: CommonError) -> Self {
+                    match err {
+                        CommonError::BadFee => Self::BadFee {
+                            expected_fee: Nat::from(0u8),
+                        },
+                        CommonError::TooOld => Self::TooOld,
+                        CommonError::CreatedInFuture { ledger_time } => {
+                            Self::CreatedInFuture { ledger_time }
+                        }
+                        CommonError::GenericError {
+                            error_code,
+                            message,
+                        } => Self::GenericError {
+                            error_code: Nat::from(error_code),
+                            message,
+                        },
+                    }
+                }
+            }
+        )*
+    };
+}
+impl_from_common_error!(TransferError, ApproveError, TransferFromError);
+
+/// Validate the arguments shared by the update methods
+pub fn validate(
+    fee: &Option<Nat>,
+    memo: &Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+    now: u64,
+) -> Result<(), CommonError> {
+    if fee.as_ref().is_some_and(|f| *f != Nat::from(0u8)) {
+        return Err(CommonError::BadFee);
+    }
+    if memo.as_ref().is_some_and(|m| m.len() > MAX_MEMO_LEN) {
+        return Err(CommonError::generic(
+            ERROR_CODE_TOO_LONG_MEMO,
+            "Memo must be at most 32 bytes",
+        ));
+    }
+    if let Some(created_at) = created_at_time {
+        if is_too_old(created_at, now) {
+            return Err(CommonError::TooOld);
+        }
+        if created_at > now.saturating_add(PERMITTED_DRIFT_NANOS) {
+            return Err(CommonError::CreatedInFuture { ledger_time: now });
+        }
+    }
+    Ok(())
+}
+
+/// Transactions created this long ago are rejected, so they no longer need to be deduplicated
+pub fn is_too_old(created_at_time: u64, now: u64) -> bool {
+    created_at_time.saturating_add(TX_WINDOW_NANOS + PERMITTED_DRIFT_NANOS) < now
+}
+
+/// Hash of the transaction to deduplicate, None unless the caller opts in by `created_at_time`
+pub fn tx_hash<T: CandidType>(
+    method: &str,
+    caller: Principal,
+    args: &T,
+    created_at_time: Option<u64>,
+) -> Option<(TxHash, u64)> {
+    let created_at = created_at_time?;
+    let bytes = Encode!(&method, &caller, args).unwrap();
+    Some((Sha256::digest(bytes).into(), created_at))
+}
+
+/// None if the amount exceeds the range of shares
+pub fn to_u128(amount: &Nat) -> Option<u128> {
+    u128::try_from(amount.0.clone()).ok()
+}
+
+pub fn metadata(fee: u128) -> Vec<(String, MetadataValue)> {
+    vec![
+        (
+            "icrc1:name".to_string(),
+            MetadataValue::Text(TOKEN_NAME.to_string()),
+        ),
+        (
+            "icrc1:symbol".to_string(),
+            MetadataValue::Text(TOKEN_SYMBOL.to_string()),
+        ),
+        (
+            "icrc1:decimals".to_string(),
+            MetadataValue::Nat(Nat::from(TOKEN_DECIMALS)),
+        ),
+        ("icrc1:fee".to_string(), MetadataValue::Nat(Nat::from(fee))),
+    ]
+}
+
+pub fn supported_standards() -> Vec<StandardRecord> {
+    vec![
+        StandardRecord {
+            name: "ICRC-1".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-1".to_string(),
+        },
+        StandardRecord {
+            name: "ICRC-2".to_string(),
+            url: "https://github.com/dfinity/ICRC-1/tree/main/standards/ICRC-2".to_string(),
+        },
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: u64 = 1_700_000_000_000_000_000;
+
+    #[test]
+    fn test_validate() {
+        assert!(validate(&None, &None, None, NOW).is_ok());
+        assert!(validate(&Some(Nat::from(0u8)), &Some(vec![0; 32]), Some(NOW), NOW).is_ok());
+        assert_eq!(
+            validate(&Some(Nat::from(1u8)), &None, None, NOW),
+            Err(CommonError::BadFee)
+        );
+        assert!(matches!(
+            validate(&None, &Some(vec![0; 33]), None, NOW),
+            Err(CommonError::GenericError {
+                error_code: ERROR_CODE_TOO_LONG_MEMO,
+                ..
+            })
+        ));
+        assert_eq!(
+            validate(
+                &None,
+                &None,
+                Some(NOW - TX_WINDOW_NANOS - PERMITTED_DRIFT_NANOS - 1),
+                NOW
+            ),
+            Err(CommonError::TooOld)
+        );
+        assert!(validate(&None, &None, Some(NOW + PERMITTED_DRIFT_NANOS), NOW).is_ok());
+        assert_eq!(
+            validate(&None, &None, Some(NOW + PERMITTED_DRIFT_NANOS + 1), NOW),
+            Err(CommonError::CreatedInFuture { ledger_time: NOW })
+        );
+    }
+
+    #[test]
+    fn test_tx_hash() {
+        let caller = Principal::anonymous();
+        let arg = TransferArg {
+            from_subaccount: None,
+            to: caller.into(),
+            amount: Nat::from(1u8),
+            fee: None,
+            memo: None,
+            created_at_time: Some(NOW),
+        };
+        let hash = tx_hash("icrc1_transfer", caller, &arg, arg.created_at_time);
+        assert_eq!(hash.map(|(_, at)| at), Some(NOW));
+        assert_eq!(
+            hash,
+            tx_hash("icrc1_transfer", caller, &arg, arg.created_at_time)
+        );
+        assert_ne!(
+            hash,
+            tx_hash("icrc2_approve", caller, &arg, arg.created_at_time)
+        );
+        let other = TransferArg {
+            memo: Some(vec![1]),
+            ..arg.clone()
+        };
+        assert_ne!(
+            hash,
+            tx_hash("icrc1_transfer", caller, &other, other.created_at_time)
+        );
+        assert_eq!(tx_hash("icrc1_transfer", caller, &arg, None), None);
+    }
+
+    #[test]
+    fn test_default_subaccount() {
+        assert!(is_default_subaccount(&None));
+        assert!(is_default_subaccount(&Some(vec![0; 32])));
+        assert!(!is_default_subaccount(&Some(vec![1; 32])));
+    }
+
+    #[test]
+    fn test_to_u128() {
+        assert_eq!(to_u128(&Nat::from(u128::MAX)), Some(u128::MAX));
+        assert_eq!(to_u128(&(Nat::from(u128::MAX) + Nat::from(1u8))), None);
+    }
+}
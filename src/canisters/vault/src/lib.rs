@@ -1,28 +1,49 @@
-use candid::{candid_method, Principal};
+use candid::{candid_method, Nat, Principal};
 use ic_cdk::{
     api::{
-        call::msg_cycles_accept128,
+        call::{msg_cycles_accept128, RejectionCode},
         canister_balance128,
         management_canister::{
             main::{canister_status, deposit_cycles},
             provisional::CanisterIdRecord,
         },
     },
-    caller, query, update,
+    caller, post_upgrade, query, update,
 };
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     DefaultMemoryImpl, StableBTreeMap,
 };
+use icp::{CyclesMinting, IcpLedger, NotifyError, NotifyTopUpArg, NotifyTopUpResult};
+use icrc::{
+    Account, Allowance, AllowanceArgs, ApproveArgs, ApproveError, CommonError, MetadataValue,
+    StandardRecord, TransferArg, TransferError, TransferFromArgs, TransferFromError, TxHash,
+};
 use std::{cell::RefCell, time::Duration};
 use types::{
-    Balance, ComponentMetricsSnapshot, CycleBalance, Index, PrincipalStorable, RefuelTarget,
+    AccessConfig, AllowanceKey, Balance, BurnRate, ComponentMetricsSnapshot, CycleBalance,
+    CycleObservation, IcpDeposit, IcpDepositError, IcpDepositKey, IcpDepositStatus, Index,
+    LegacyRefuelTarget, PendingWithdrawal, PredictiveRefuelConfig, PrincipalStorable, RefuelError,
+    RefuelEvent, RefuelOutcome, RefuelResult, RefuelTarget, RefuelTargetEntry, RefuelTrigger, Role,
+    Roles, ShareAllowance, ShareAmount, ShareApproval, ShareTransfer, TransferShareError,
+    WithdrawError,
 };
+mod icp;
+mod icrc;
+mod predictive;
 mod types;
 
 type MemoryType = VirtualMemory<DefaultMemoryImpl>;
 
 const MONITROING_INTERVAL_SECS: u64 = 3600;
+const MAX_REFUEL_ERROR_MESSAGE_LEN: usize = 256;
+const MAX_REFUEL_EVENTS_PAGE_SIZE: u64 = 100;
+const MAX_SHARE_TRANSFERS_PAGE_SIZE: u64 = 100;
+const MAX_SHARE_APPROVALS_PAGE_SIZE: u64 = 100;
+/// NOTE: Bounded by bytes rather than chars, so that the entry fits in RefuelTargetEntry::MAX_SIZE
+const MAX_REFUEL_TARGET_LABEL_BYTES: usize = 64;
+const MIN_PREDICTED_REFUEL_DELAY_SECS: u64 = 600;
+const MIN_MANUAL_REFUEL_INTERVAL_SECS: u64 = 60;
 
 // NOTE: All storage uses stable memory, so no memory for upgrades is needed.
 // const MEMORY_ID_FOR_UPGRADE: MemoryId = MemoryId::new(0);
@@ -37,7 +58,8 @@ thread_local! {
             MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(1))),
         )
     );
-    static REFUEL_TARGETS: RefCell<ic_stable_structures::Vec<RefuelTarget, MemoryType>> = RefCell::new(
+    // NOTE: Legacy layout, migrated to REFUEL_TARGET_ENTRIES on upgrade
+    static REFUEL_TARGETS: RefCell<ic_stable_structures::Vec<LegacyRefuelTarget, MemoryType>> = RefCell::new(
         ic_stable_structures::Vec::init(MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(2)))).unwrap()
     );
     static CUMULATIVE_REFUELED: RefCell<StableBTreeMap<PrincipalStorable, u128, MemoryType>> = RefCell::new(
@@ -66,6 +88,99 @@ thread_local! {
             Index::default(),
          ).unwrap()
     );
+    static OBSERVED_CYCLES: RefCell<StableBTreeMap<PrincipalStorable, CycleObservation, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(8))),
+        )
+    );
+    static PENDING_WITHDRAWALS: RefCell<StableBTreeMap<u64, PendingWithdrawal, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(9))),
+        )
+    );
+    static LAST_REFUEL_RESULTS: RefCell<StableBTreeMap<PrincipalStorable, RefuelResult, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(10))),
+        )
+    );
+    // NOTE: Append-only, keyed by the sequence number in the order of timestamps
+    static REFUEL_EVENTS: RefCell<StableBTreeMap<u64, RefuelEvent, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(11))),
+        )
+    );
+    static REFUEL_TARGET_ENTRIES: RefCell<StableBTreeMap<PrincipalStorable, RefuelTargetEntry, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(12))),
+        )
+    );
+    static BURN_RATES: RefCell<StableBTreeMap<PrincipalStorable, BurnRate, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(13))),
+        )
+    );
+    static PREDICTIVE_REFUEL_CONFIG: RefCell<ic_stable_structures::StableCell<PredictiveRefuelConfig, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(14))),
+            PredictiveRefuelConfig::default(),
+         ).unwrap()
+    );
+    static ROLES: RefCell<StableBTreeMap<PrincipalStorable, Roles, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(15))),
+        )
+    );
+    static SHARE_TRANSFERS: RefCell<StableBTreeMap<u64, ShareTransfer, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(17))),
+        )
+    );
+    static ALLOWANCES: RefCell<StableBTreeMap<AllowanceKey, ShareAllowance, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(18))),
+        )
+    );
+    static ICP_DEPOSITS: RefCell<StableBTreeMap<IcpDepositKey, IcpDeposit, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(19))),
+        )
+    );
+    static ACCESS_CONFIG: RefCell<ic_stable_structures::StableCell<AccessConfig, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(16))),
+            AccessConfig::default(),
+         ).unwrap()
+    );
+    // NOTE: 0 for vaults installed before the interval was stored, set by `set_refueling_interval`
+    static REFUELING_INTERVAL_SECS: RefCell<ic_stable_structures::StableCell<u64, MemoryType>> = RefCell::new(
+        ic_stable_structures::StableCell::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(20))),
+            0,
+         ).unwrap()
+    );
+    // hash -> index of the transaction, for the ICRC transactions with `created_at_time`
+    static RECENT_TRANSACTIONS: RefCell<StableBTreeMap<TxHash, u64, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(21))),
+        )
+    );
+    // (created_at_time, hash), to prune the transactions out of the window
+    static RECENT_TRANSACTION_ORDER: RefCell<StableBTreeMap<(u64, TxHash), (), MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(22))),
+        )
+    );
+    // NOTE: Append-only, the index is returned by `icrc2_approve`
+    static SHARE_APPROVALS: RefCell<StableBTreeMap<u64, ShareApproval, MemoryType>> = RefCell::new(
+        StableBTreeMap::init(
+            MEMORY_MANAGER.with(|m| m.borrow().get(MemoryId::new(23))),
+        )
+    );
+
+    // heap memory
+    static PREDICTED_REFUEL_TIMER_ID: RefCell<Option<ic_cdk_timers::TimerId>> = RefCell::new(None);
+    static REFUELING_TIMER_ID: RefCell<Option<ic_cdk_timers::TimerId>> = RefCell::new(None);
+    static LAST_MANUAL_REFUEL_AT: RefCell<u64> = const { RefCell::new(0) };
 }
 
 #[ic_cdk::init]
@@ -80,7 +195,10 @@ async fn init(
     _set_target_canister(target_canister);
     increase_index(&initial_supply, deployer);
     start_refueling(refueling_interval_secs);
-    refuel_targets.iter().for_each(_put_refuel_target);
+    let now = ic_cdk::api::time();
+    refuel_targets
+        .iter()
+        .for_each(|t| _put_refuel_target(t, now));
     refuel_targets_inital_supply
         .iter()
         .for_each(|(id, amount)| {
@@ -92,6 +210,9 @@ async fn init(
 #[update]
 #[candid_method(update)]
 fn supply(principal: Option<Principal>) {
+    if get_access_config().is_depositor_only {
+        assert_role(Role::Depositor);
+    }
     increase_index(
         &msg_cycles_accept128(u128::MAX).into(),
         principal.unwrap_or(caller()),
@@ -100,20 +221,352 @@ fn supply(principal: Option<Principal>) {
 
 #[update]
 #[candid_method(update)]
-async fn withdraw(delta: Balance) {
+async fn withdraw(delta: Balance) -> Result<(), WithdrawError> {
     let principal = caller();
-    if withdrawable_of(principal).lt(&delta) {
-        panic!("Not enough withdrawable balance");
+    let withdrawable = withdrawable_of(principal);
+    if withdrawable.lt(&delta) {
+        return Err(WithdrawError::InsufficientBalance { withdrawable });
     }
-    decrease_index(&delta, principal);
-    deposit_cycles(
+    // NOTE: The shares are reserved before the await, so that they cannot be withdrawn twice
+    let id = reserve_withdrawal(principal, &delta, ic_cdk::api::time());
+    let res = deposit_cycles(
         CanisterIdRecord {
             canister_id: principal,
         },
         delta.into(),
     )
+    .await;
+    match res {
+        Ok(_) => {
+            commit_withdrawal(id);
+            Ok(())
+        }
+        Err((code, msg)) => {
+            restore_withdrawal(id);
+            Err(WithdrawError::DepositFailed(format!("{:?}: {}", code, msg)))
+        }
+    }
+}
+
+fn reserve_withdrawal(principal: Principal, delta: &Balance, created_at: u64) -> u64 {
+    let share = index().share(delta, &total_supply());
+    decrease_index(delta, principal);
+    PENDING_WITHDRAWALS.with(|m| {
+        let mut pendings = m.borrow_mut();
+        let id = pendings
+            .last_key_value()
+            .map(|(k, _)| k + 1)
+            .unwrap_or_default();
+        pendings.insert(
+            id,
+            PendingWithdrawal {
+                principal,
+                amount: delta.clone(),
+                share,
+                created_at,
+            },
+        );
+        id
+    })
+}
+
+fn commit_withdrawal(id: u64) {
+    PENDING_WITHDRAWALS.with(|m| m.borrow_mut().remove(&id));
+}
+
+/// Undo the reservation exactly, so that the sum of shares keeps matching the index
+fn restore_withdrawal(id: u64) {
+    let Some(pending) = PENDING_WITHDRAWALS.with(|m| m.borrow_mut().remove(&id)) else {
+        return;
+    };
+    SHARE_MAP.with(|m| {
+        let share = m
+            .borrow()
+            .get(&pending.principal.into())
+            .unwrap_or_default();
+        m.borrow_mut()
+            .insert(pending.principal.into(), share.add(&pending.share));
+    });
+    set_index(index().add(&pending.share));
+    add_total_supply(&pending.amount, false);
+}
+
+#[query]
+#[candid_method(query)]
+fn pending_withdrawals() -> Vec<(u64, PendingWithdrawal)> {
+    PENDING_WITHDRAWALS.with(|m| m.borrow().iter().collect())
+}
+
+/// NOTE: Timers are dropped on upgrades, so the refueling is started again with the stored interval
+#[post_upgrade]
+fn post_upgrade() {
+    migrate_refuel_targets(ic_cdk::api::time());
+    reconcile_pending_withdrawals();
+    let interval_secs = refueling_interval_secs();
+    if interval_secs > 0 {
+        start_refueling(interval_secs);
+    } else {
+        ic_cdk::println!("Refueling interval is not set");
+    }
+    schedule_predicted_refuel();
+}
+
+/// Restore the shares of the withdrawals left pending across an upgrade
+/// NOTE: Stopping the vault for the upgrade waits for the deposits in flight, so the withdrawals left pending
+///       lost their callback. Whether their deposit completed is unknown, and the shares are given back to the withdrawer.
+fn reconcile_pending_withdrawals() {
+    let ids: Vec<u64> = PENDING_WITHDRAWALS.with(|m| m.borrow().iter().map(|(id, _)| id).collect());
+    for id in ids {
+        ic_cdk::println!("Restored pending withdrawal: {}", id);
+        restore_withdrawal(id);
+    }
+}
+
+/// Resolve a withdrawal left pending by a trapped callback, before the next upgrade restores it
+/// NOTE: The vault cannot tell whether the deposit completed, so the admin checks it and commits or restores the shares
+#[update]
+#[candid_method(update)]
+fn resolve_pending_withdrawal(id: u64, is_deposited: bool) {
+    assert_role(Role::Admin);
+    _resolve_pending_withdrawal(id, is_deposited);
+}
+
+fn _resolve_pending_withdrawal(id: u64, is_deposited: bool) {
+    let exists = PENDING_WITHDRAWALS.with(|m| m.borrow().contains_key(&id));
+    assert!(exists, "Pending withdrawal not found");
+    if is_deposited {
+        commit_withdrawal(id);
+    } else {
+        restore_withdrawal(id);
+    }
+}
+
+/// Account on the ICP ledger to transfer ICP to before `notify_icp_deposit`
+#[query]
+#[candid_method(query)]
+fn icp_deposit_account(principal: Principal) -> Account {
+    icp::deposit_account(ic_cdk::id(), principal)
+}
+
+/// Convert the ICP in the deposit account of the caller to cycles and credit them as shares
+/// NOTE: The deposit is identified by the block of the transfer to the CMC made by the vault, so it is converted at most once.
+/// Pass `top_up_block` to notify the CMC again of a deposit whose notification failed.
+/// The argument is not the block of a transfer made by the depositor, as in `notify_icp_deposit(block_index)`:
+/// the vault cannot tell who made a transfer to its account, so it moves the balance of the deposit account itself
+/// and `None` starts a deposit.
+#[update]
+#[candid_method(update)]
+async fn notify_icp_deposit(top_up_block: Option<u64>) -> Result<u128, IcpDepositError> {
+    if get_access_config().is_depositor_only {
+        assert_role(Role::Depositor);
+    }
+    _notify_icp_deposit(
+        &icp::ledger(),
+        &icp::cmc(),
+        ic_cdk::id(),
+        caller(),
+        top_up_block,
+        ic_cdk::api::time(),
+    )
     .await
-    .unwrap();
+}
+
+async fn _notify_icp_deposit(
+    ledger: &impl IcpLedger,
+    cmc: &impl CyclesMinting,
+    vault: Principal,
+    caller: Principal,
+    top_up_block: Option<u64>,
+    now: u64,
+) -> Result<u128, IcpDepositError> {
+    let deposit = match top_up_block {
+        Some(top_up_block) => {
+            icp_deposit_of(caller, top_up_block).ok_or(IcpDepositError::NotFound)?
+        }
+        None => {
+            let deposit = IcpDeposit {
+                depositor: caller,
+                notified_at: now,
+                status: transfer_icp_deposit(ledger, vault, caller).await?,
+            };
+            if let IcpDepositStatus::Transferred { top_up_block, .. } = deposit.status {
+                put_icp_deposit(caller, top_up_block, deposit.clone());
+            }
+            deposit
+        }
+    };
+    let (top_up_block, amount_e8s) = match deposit.status {
+        // NOTE: A notification whose callback trapped is never finished, so it is notified again after the timeout
+        IcpDepositStatus::Notifying {
+            top_up_block,
+            amount_e8s,
+            since,
+        } => {
+            if now.saturating_sub(since) < icp::NOTIFY_IN_FLIGHT_TIMEOUT_SECS * 1000 * 1000000 {
+                return Err(IcpDepositError::Processing);
+            }
+            (top_up_block, amount_e8s)
+        }
+        IcpDepositStatus::Completed { cycles, .. } => {
+            return Err(IcpDepositError::AlreadyCompleted { cycles })
+        }
+        IcpDepositStatus::Transferred {
+            top_up_block,
+            amount_e8s,
+        } => (top_up_block, amount_e8s),
+    };
+    let transferred = IcpDeposit {
+        status: IcpDepositStatus::Transferred {
+            top_up_block,
+            amount_e8s,
+        },
+        ..deposit.clone()
+    };
+    // NOTE: Marked before the await, so that the block is not notified concurrently
+    put_icp_deposit(
+        caller,
+        top_up_block,
+        IcpDeposit {
+            status: IcpDepositStatus::Notifying {
+                top_up_block,
+                amount_e8s,
+                since: now,
+            },
+            ..deposit.clone()
+        },
+    );
+    let res = cmc
+        .notify_top_up(NotifyTopUpArg {
+            block_index: top_up_block,
+            canister_id: vault,
+        })
+        .await;
+    match res {
+        Ok(NotifyTopUpResult::Ok(cycles)) => {
+            // NOTE: The CMC answers every notification of the block, so it is credited only if not yet
+            if let Some(IcpDepositStatus::Completed { cycles, .. }) =
+                icp_deposit_of(caller, top_up_block).map(|d| d.status)
+            {
+                return Err(IcpDepositError::AlreadyCompleted { cycles });
+            }
+            let cycles = icrc::to_u128(&cycles).unwrap_or(u128::MAX);
+            increase_index(&cycles.into(), caller);
+            put_icp_deposit(
+                caller,
+                top_up_block,
+                IcpDeposit {
+                    status: IcpDepositStatus::Completed {
+                        top_up_block,
+                        cycles,
+                    },
+                    ..deposit
+                },
+            );
+            Ok(cycles)
+        }
+        Ok(NotifyTopUpResult::Err(NotifyError::Refunded { reason, .. })) => {
+            remove_icp_deposit(caller, top_up_block);
+            Err(IcpDepositError::Refunded(reason))
+        }
+        // NOTE: The deposit is kept at the CMC, so it is marked as transferred again to be retried
+        Ok(NotifyTopUpResult::Err(err)) => {
+            put_icp_deposit(caller, top_up_block, transferred);
+            Err(IcpDepositError::NotifyFailed(format!(
+                "{:?} ({} e8s in block {})",
+                err, amount_e8s, top_up_block
+            )))
+        }
+        Err((code, msg)) => {
+            put_icp_deposit(caller, top_up_block, transferred);
+            Err(IcpDepositError::NotifyFailed(format!(
+                "{:?}: {}",
+                code, msg
+            )))
+        }
+    }
+}
+
+/// Transfer the whole balance of the deposit account to the CMC
+async fn transfer_icp_deposit(
+    ledger: &impl IcpLedger,
+    vault: Principal,
+    depositor: Principal,
+) -> Result<IcpDepositStatus, IcpDepositError> {
+    let balance = ledger
+        .icrc1_balance_of(icp::deposit_account(vault, depositor))
+        .await
+        .map_err(|(code, msg)| IcpDepositError::TransferFailed(format!("{:?}: {}", code, msg)))?;
+    let balance_e8s = icrc::to_u128(&balance)
+        .unwrap_or(u128::MAX)
+        .min(u64::MAX as u128) as u64;
+    if balance_e8s <= icp::ICP_FEE_E8S {
+        return Err(IcpDepositError::InsufficientDeposit { balance_e8s });
+    }
+    let amount_e8s = balance_e8s - icp::ICP_FEE_E8S;
+    let res = ledger
+        .icrc1_transfer(icp::top_up_transfer(vault, depositor, amount_e8s))
+        .await;
+    match res {
+        Ok(Ok(top_up_block)) => {
+            let Some(block) = icrc::to_u128(&top_up_block).and_then(|b| u64::try_from(b).ok())
+            else {
+                return Err(IcpDepositError::TransferFailed(format!(
+                    "Unexpected block of the transfer to the CMC: {}",
+                    top_up_block
+                )));
+            };
+            Ok(IcpDepositStatus::Transferred {
+                top_up_block: block,
+                amount_e8s,
+            })
+        }
+        Ok(Err(err)) => Err(IcpDepositError::TransferFailed(format!("{:?}", err))),
+        Err((code, msg)) => Err(IcpDepositError::TransferFailed(format!(
+            "{:?}: {}",
+            code, msg
+        ))),
+    }
+}
+
+/// Deposits of the depositor keyed by the block of the transfer to the CMC
+#[query]
+#[candid_method(query)]
+fn get_icp_deposits(depositor: Principal) -> Vec<(u64, IcpDeposit)> {
+    let key = |top_up_block| IcpDepositKey {
+        depositor,
+        top_up_block,
+    };
+    ICP_DEPOSITS.with(|m| {
+        m.borrow()
+            .range(key(0)..=key(u64::MAX))
+            .map(|(k, deposit)| (k.top_up_block, deposit))
+            .collect()
+    })
+}
+
+fn icp_deposit_of(depositor: Principal, top_up_block: u64) -> Option<IcpDeposit> {
+    ICP_DEPOSITS.with(|m| {
+        m.borrow().get(&IcpDepositKey {
+            depositor,
+            top_up_block,
+        })
+    })
+}
+
+fn put_icp_deposit(depositor: Principal, top_up_block: u64, deposit: IcpDeposit) {
+    let key = IcpDepositKey {
+        depositor,
+        top_up_block,
+    };
+    ICP_DEPOSITS.with(|m| m.borrow_mut().insert(key, deposit));
+}
+
+fn remove_icp_deposit(depositor: Principal, top_up_block: u64) {
+    let key = IcpDepositKey {
+        depositor,
+        top_up_block,
+    };
+    ICP_DEPOSITS.with(|m| m.borrow_mut().remove(&key));
 }
 
 #[query]
@@ -164,6 +617,434 @@ fn share_of(principal: Principal) -> Index {
     SHARE_MAP.with(|m| m.borrow().get(&principal.into()).unwrap_or_default())
 }
 
+/// Move the shares of the caller to `to`, returns the id of the transfer
+/// NOTE: The index and the total supply are unchanged, so the balances of the others are too
+#[update]
+#[candid_method(update)]
+fn transfer_share(to: Principal, amount: ShareAmount) -> Result<u64, TransferShareError> {
+    _transfer_share(caller(), to, amount, ic_cdk::api::time())
+}
+
+fn _transfer_share(
+    from: Principal,
+    to: Principal,
+    amount: ShareAmount,
+    now: u64,
+) -> Result<u64, TransferShareError> {
+    if from == to {
+        return Err(TransferShareError::SelfTransfer);
+    }
+    let share = match amount {
+        ShareAmount::Balance(balance) => index().share(&balance, &total_supply()),
+        ShareAmount::Share(share) => share,
+    };
+    move_share(from, to, share, None, None, now)
+}
+
+fn move_share(
+    from: Principal,
+    to: Principal,
+    share: Index,
+    spender: Option<Principal>,
+    memo: Option<Vec<u8>>,
+    now: u64,
+) -> Result<u64, TransferShareError> {
+    if from == to {
+        return Err(TransferShareError::SelfTransfer);
+    }
+    if share == Index::default() {
+        return Err(TransferShareError::ZeroAmount);
+    }
+    let available = share_of(from);
+    if available < share {
+        return Err(TransferShareError::InsufficientShare { available });
+    }
+    SHARE_MAP.with(|m| {
+        let mut shares = m.borrow_mut();
+        let to_share = shares.get(&to.into()).unwrap_or_default();
+        match available == share {
+            true => shares.remove(&from.into()),
+            false => shares.insert(from.into(), available.sub(&share)),
+        };
+        shares.insert(to.into(), to_share.add(&share));
+    });
+    let amount = share.to_balance(&index(), &total_supply());
+    Ok(append_share_transfer(ShareTransfer {
+        timestamp: now,
+        from,
+        to,
+        share,
+        amount,
+        spender,
+        memo,
+    }))
+}
+
+fn append_share_transfer(transfer: ShareTransfer) -> u64 {
+    SHARE_TRANSFERS.with(|m| {
+        let mut transfers = m.borrow_mut();
+        let id = transfers.len();
+        transfers.insert(id, transfer);
+        id
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_share_transfers_count() -> u64 {
+    SHARE_TRANSFERS.with(|m| m.borrow().len())
+}
+
+/// Share transfers from the oldest, `limit` is capped by `MAX_SHARE_TRANSFERS_PAGE_SIZE`
+#[query]
+#[candid_method(query)]
+fn get_share_transfers(offset: u64, limit: u64) -> Vec<(u64, ShareTransfer)> {
+    let limit = limit.min(MAX_SHARE_TRANSFERS_PAGE_SIZE);
+    SHARE_TRANSFERS.with(|m| m.borrow().range(offset..).take(limit as usize).collect())
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc1_name() -> String {
+    icrc::TOKEN_NAME.to_string()
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc1_symbol() -> String {
+    icrc::TOKEN_SYMBOL.to_string()
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc1_decimals() -> u8 {
+    icrc::TOKEN_DECIMALS
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc1_fee() -> Nat {
+    Nat::from(0u8)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc1_metadata() -> Vec<(String, MetadataValue)> {
+    icrc::metadata(0)
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc1_total_supply() -> Nat {
+    Nat::from(Into::<u128>::into(index()))
+}
+
+/// Shares are minted by `supply` and burned by `withdraw` of the vault itself
+#[query]
+#[candid_method(query)]
+fn icrc1_minting_account() -> Option<Account> {
+    Some(ic_cdk::id().into())
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc1_balance_of(account: Account) -> Nat {
+    match account.is_default() {
+        true => Nat::from(Into::<u128>::into(share_of(account.owner))),
+        false => Nat::from(0u8),
+    }
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc1_supported_standards() -> Vec<StandardRecord> {
+    icrc::supported_standards()
+}
+
+#[update]
+#[candid_method(update)]
+fn icrc1_transfer(arg: TransferArg) -> Result<Nat, TransferError> {
+    assert_not_minting_account(&arg.to).map_err(TransferError::from)?;
+    _icrc1_transfer(caller(), arg, ic_cdk::api::time())
+}
+
+fn _icrc1_transfer(caller: Principal, arg: TransferArg, now: u64) -> Result<Nat, TransferError> {
+    icrc::validate(&arg.fee, &arg.memo, arg.created_at_time, now)?;
+    if !icrc::is_default_subaccount(&arg.from_subaccount) || !arg.to.is_default() {
+        return Err(unsupported_subaccount().into());
+    }
+    let tx = icrc::tx_hash("icrc1_transfer", caller, &arg, arg.created_at_time);
+    if let Some(duplicate_of) = find_duplicate(&tx, now) {
+        return Err(TransferError::Duplicate {
+            duplicate_of: Nat::from(duplicate_of),
+        });
+    }
+    let balance = share_of(caller);
+    let share = icrc::to_u128(&arg.amount).ok_or(TransferError::InsufficientFunds {
+        balance: Nat::from(Into::<u128>::into(balance.clone())),
+    })?;
+    let id =
+        move_share(caller, arg.to.owner, share.into(), None, arg.memo, now).map_err(
+            |e| match e {
+                TransferShareError::InsufficientShare { available } => {
+                    TransferError::InsufficientFunds {
+                        balance: Nat::from(Into::<u128>::into(available)),
+                    }
+                }
+                e => invalid_transfer(e).into(),
+            },
+        )?;
+    record_transaction(tx, id);
+    Ok(Nat::from(id))
+}
+
+/// NOTE: Approvals are logged apart from the transfers, the returned index is in `get_share_approvals`
+#[update]
+#[candid_method(update)]
+fn icrc2_approve(args: ApproveArgs) -> Result<Nat, ApproveError> {
+    _icrc2_approve(caller(), args, ic_cdk::api::time())
+}
+
+fn _icrc2_approve(caller: Principal, args: ApproveArgs, now: u64) -> Result<Nat, ApproveError> {
+    icrc::validate(&args.fee, &args.memo, args.created_at_time, now)?;
+    if !icrc::is_default_subaccount(&args.from_subaccount) || !args.spender.is_default() {
+        return Err(unsupported_subaccount().into());
+    }
+    if args.spender.owner == caller {
+        return Err(
+            CommonError::generic(icrc::ERROR_CODE_SELF_APPROVAL, "Cannot approve self").into(),
+        );
+    }
+    if args.expires_at.is_some_and(|at| at <= now) {
+        return Err(ApproveError::Expired { ledger_time: now });
+    }
+    let tx = icrc::tx_hash("icrc2_approve", caller, &args, args.created_at_time);
+    if let Some(duplicate_of) = find_duplicate(&tx, now) {
+        return Err(ApproveError::Duplicate {
+            duplicate_of: Nat::from(duplicate_of),
+        });
+    }
+    let key = AllowanceKey {
+        owner: caller,
+        spender: args.spender.owner,
+    };
+    let current = allowance_of(&key, now);
+    if let Some(expected) = args.expected_allowance {
+        if icrc::to_u128(&expected) != Some(current.clone().into()) {
+            return Err(ApproveError::AllowanceChanged {
+                current_allowance: Nat::from(Into::<u128>::into(current)),
+            });
+        }
+    }
+    // NOTE: Allowances over the range of shares cannot be spent anyway
+    let share = icrc::to_u128(&args.amount).unwrap_or(u128::MAX);
+    ALLOWANCES.with(|m| match share {
+        0 => m.borrow_mut().remove(&key),
+        _ => m.borrow_mut().insert(
+            key,
+            ShareAllowance {
+                share: share.into(),
+                expires_at: args.expires_at,
+            },
+        ),
+    });
+    let id = append_share_approval(ShareApproval {
+        timestamp: now,
+        owner: caller,
+        spender: args.spender.owner,
+        share: share.into(),
+        expires_at: args.expires_at,
+        memo: args.memo,
+    });
+    record_transaction(tx, id);
+    Ok(Nat::from(id))
+}
+
+fn append_share_approval(approval: ShareApproval) -> u64 {
+    SHARE_APPROVALS.with(|m| {
+        let mut approvals = m.borrow_mut();
+        let id = approvals.len();
+        approvals.insert(id, approval);
+        id
+    })
+}
+
+#[query]
+#[candid_method(query)]
+fn get_share_approvals_count() -> u64 {
+    SHARE_APPROVALS.with(|m| m.borrow().len())
+}
+
+/// Share approvals from the oldest, `limit` is capped by `MAX_SHARE_APPROVALS_PAGE_SIZE`
+#[query]
+#[candid_method(query)]
+fn get_share_approvals(offset: u64, limit: u64) -> Vec<(u64, ShareApproval)> {
+    let limit = limit.min(MAX_SHARE_APPROVALS_PAGE_SIZE);
+    SHARE_APPROVALS.with(|m| m.borrow().range(offset..).take(limit as usize).collect())
+}
+
+#[query]
+#[candid_method(query)]
+fn icrc2_allowance(args: AllowanceArgs) -> Allowance {
+    _icrc2_allowance(args, ic_cdk::api::time())
+}
+
+fn _icrc2_allowance(args: AllowanceArgs, now: u64) -> Allowance {
+    if !args.account.is_default() || !args.spender.is_default() {
+        return Allowance {
+            allowance: Nat::from(0u8),
+            expires_at: None,
+        };
+    }
+    let key = AllowanceKey {
+        owner: args.account.owner,
+        spender: args.spender.owner,
+    };
+    let expires_at = ALLOWANCES.with(|m| m.borrow().get(&key).and_then(|a| a.expires_at));
+    Allowance {
+        allowance: Nat::from(Into::<u128>::into(allowance_of(&key, now))),
+        expires_at,
+    }
+}
+
+#[update]
+#[candid_method(update)]
+fn icrc2_transfer_from(args: TransferFromArgs) -> Result<Nat, TransferFromError> {
+    assert_not_minting_account(&args.to).map_err(TransferFromError::from)?;
+    _icrc2_transfer_from(caller(), args, ic_cdk::api::time())
+}
+
+fn _icrc2_transfer_from(
+    caller: Principal,
+    args: TransferFromArgs,
+    now: u64,
+) -> Result<Nat, TransferFromError> {
+    icrc::validate(&args.fee, &args.memo, args.created_at_time, now)?;
+    if !icrc::is_default_subaccount(&args.spender_subaccount)
+        || !args.from.is_default()
+        || !args.to.is_default()
+    {
+        return Err(unsupported_subaccount().into());
+    }
+    let tx = icrc::tx_hash("icrc2_transfer_from", caller, &args, args.created_at_time);
+    if let Some(duplicate_of) = find_duplicate(&tx, now) {
+        return Err(TransferFromError::Duplicate {
+            duplicate_of: Nat::from(duplicate_of),
+        });
+    }
+    let owner = args.from.owner;
+    let balance = share_of(owner);
+    let share = icrc::to_u128(&args.amount).ok_or(TransferFromError::InsufficientFunds {
+        balance: Nat::from(Into::<u128>::into(balance.clone())),
+    })?;
+    let share = Index::from(share);
+    let key = AllowanceKey {
+        owner,
+        spender: caller,
+    };
+    // NOTE: The owner spends its own shares without any allowance
+    let allowance = allowance_of(&key, now);
+    if caller != owner && allowance < share {
+        return Err(TransferFromError::InsufficientAllowance {
+            allowance: Nat::from(Into::<u128>::into(allowance)),
+        });
+    }
+    let id = move_share(
+        owner,
+        args.to.owner,
+        share.clone(),
+        Some(caller),
+        args.memo,
+        now,
+    )
+    .map_err(|e| match e {
+        TransferShareError::InsufficientShare { available } => {
+            TransferFromError::InsufficientFunds {
+                balance: Nat::from(Into::<u128>::into(available)),
+            }
+        }
+        e => invalid_transfer(e).into(),
+    })?;
+    if caller != owner {
+        ALLOWANCES.with(|m| {
+            let mut allowances = m.borrow_mut();
+            let expires_at = allowances.get(&key).and_then(|a| a.expires_at);
+            match allowance == share {
+                true => allowances.remove(&key),
+                false => allowances.insert(
+                    key,
+                    ShareAllowance {
+                        share: allowance.sub(&share),
+                        expires_at,
+                    },
+                ),
+            };
+        });
+    }
+    record_transaction(tx, id);
+    Ok(Nat::from(id))
+}
+
+/// Index of the same transaction in the window, after pruning the transactions out of it
+fn find_duplicate(tx: &Option<(TxHash, u64)>, now: u64) -> Option<u64> {
+    prune_recent_transactions(now);
+    let (hash, _) = tx.as_ref()?;
+    RECENT_TRANSACTIONS.with(|m| m.borrow().get(hash))
+}
+
+fn record_transaction(tx: Option<(TxHash, u64)>, id: u64) {
+    let Some((hash, created_at)) = tx else {
+        return;
+    };
+    RECENT_TRANSACTIONS.with(|m| m.borrow_mut().insert(hash, id));
+    RECENT_TRANSACTION_ORDER.with(|m| m.borrow_mut().insert((created_at, hash), ()));
+}
+
+/// NOTE: Transactions out of the window are rejected as too old, so they cannot be duplicated
+fn prune_recent_transactions(now: u64) {
+    while let Some(((created_at, hash), _)) =
+        RECENT_TRANSACTION_ORDER.with(|m| m.borrow().first_key_value())
+    {
+        if !icrc::is_too_old(created_at, now) {
+            break;
+        }
+        RECENT_TRANSACTION_ORDER.with(|m| m.borrow_mut().remove(&(created_at, hash)));
+        RECENT_TRANSACTIONS.with(|m| m.borrow_mut().remove(&hash));
+    }
+}
+
+/// Allowance of the spender, zero if expired
+fn allowance_of(key: &AllowanceKey, now: u64) -> Index {
+    ALLOWANCES.with(|m| {
+        m.borrow()
+            .get(key)
+            .filter(|a| a.expires_at.is_none_or(|at| now < at))
+            .map(|a| a.share)
+            .unwrap_or_default()
+    })
+}
+
+fn assert_not_minting_account(account: &Account) -> Result<(), CommonError> {
+    match account.owner == ic_cdk::id() {
+        true => Err(CommonError::generic(
+            icrc::ERROR_CODE_MINTING_ACCOUNT,
+            "Shares are burned by withdraw",
+        )),
+        false => Ok(()),
+    }
+}
+
+fn unsupported_subaccount() -> CommonError {
+    CommonError::generic(
+        icrc::ERROR_CODE_UNSUPPORTED_SUBACCOUNT,
+        "Only the default subaccount is supported",
+    )
+}
+
+fn invalid_transfer(err: TransferShareError) -> CommonError {
+    CommonError::generic(icrc::ERROR_CODE_INVALID_TRANSFER, &format!("{:?}", err))
+}
+
 fn increase_index(delta: &Balance, principal: Principal) {
     add_share(principal, delta, false);
     add_index(delta, false);
@@ -228,78 +1109,353 @@ fn receive_revenue() {
 #[update]
 #[candid_method(update)]
 async fn refuel() {
+    assert_role(Role::Operator);
+    let now = ic_cdk::api::time() / (1000 * 1000000);
+    let last = LAST_MANUAL_REFUEL_AT.with(|t| *t.borrow());
+    assert!(
+        now >= last + MIN_MANUAL_REFUEL_INTERVAL_SECS,
+        "Refueled too recently"
+    );
+    LAST_MANUAL_REFUEL_AT.with(|t| *t.borrow_mut() = now);
+    _refuel(RefuelTrigger::Manual(caller())).await;
+}
+
+async fn _refuel(trigger: RefuelTrigger) {
     ic_cdk::println!("Start refueling...");
-    for target in get_refuel_targets() {
-        let res = canister_status(CanisterIdRecord {
-            canister_id: target.id,
-        })
-        .await;
-        if let Ok(status) = res {
-            let balance = status.0.cycles;
-            ic_cdk::println!(
-                "[{}] balance: {}",
-                target.id.to_string(),
-                balance.to_string(),
-            );
-            if balance > target.threshold {
-                ic_cdk::println!(
-                    "[{}] skip refueling: threshold={}",
-                    target.id.to_string(),
-                    target.threshold.to_string(),
-                );
-                continue;
-            }
+    // NOTE: Every target is processed even if others fail
+    for target in refuel_target_entries()
+        .into_iter()
+        .filter(|e| !e.is_paused)
+        .map(|e| e.target)
+    {
+        let outcome = refuel_target(&target, &trigger).await;
+        if let RefuelOutcome::Failed(err) = &outcome {
+            ic_cdk::println!("[{}] failed to refuel: {:?}", target.id.to_string(), err);
         }
-        // TODO handle error except out of cycles
-        deposit_cycles(
-            CanisterIdRecord {
-                canister_id: target.id,
+        record_refuel_result(
+            target.id,
+            RefuelResult {
+                timestamp: ic_cdk::api::time(),
+                outcome,
             },
-            target.amount,
-        )
-        .await
-        .unwrap();
-        record_cumulative_refueled(target.id, target.amount);
+        );
+    }
+    schedule_predicted_refuel();
+}
+
+async fn refuel_target(target: &RefuelTarget, trigger: &RefuelTrigger) -> RefuelOutcome {
+    let res = canister_status(CanisterIdRecord {
+        canister_id: target.id,
+    })
+    .await;
+    // NOTE: Cycles are not deposited blindly when the balance of the target is unknown
+    let status = match res {
+        Ok(status) => status.0,
+        Err((code, msg)) => return RefuelOutcome::Failed(classify_refuel_error(code, &msg)),
+    };
+    let balance = status.cycles;
+    let balance_u128 = u128::try_from(balance.0.clone()).unwrap_or(u128::MAX);
+    let idle_cycles_per_day =
+        u128::try_from(status.idle_cycles_burned_per_day.0).unwrap_or(u128::MAX);
+    let now = ic_cdk::api::time();
+    // NOTE: The burn is measured against the previous observation, so it must be updated first
+    let burn_rate = update_burn_rate(target.id, balance_u128, idle_cycles_per_day, now);
+    record_observed_cycles(target.id, balance_u128);
+    ic_cdk::println!(
+        "[{}] balance: {}",
+        target.id.to_string(),
+        balance.to_string(),
+    );
+    let config = get_predictive_refuel_config();
+    let required = match config.is_enabled {
+        true => predictive::required_balance(target.threshold, &burn_rate, config.runway_secs),
+        false => target.threshold,
+    };
+    if balance_u128 > required {
         ic_cdk::println!(
-            "[{}] refueled: {} ",
+            "[{}] skip refueling: threshold={}, required={}",
             target.id.to_string(),
-            target.amount.to_string(),
+            target.threshold.to_string(),
+            required.to_string(),
         );
+        return RefuelOutcome::Skipped {
+            balance: balance_u128,
+        };
+    }
+    let amount = match config.is_enabled {
+        true => predictive::runway_deposit(target, balance_u128, required),
+        false => target.deposit_amount(balance_u128),
+    };
+    if amount == 0 {
+        return RefuelOutcome::Skipped {
+            balance: balance_u128,
+        };
+    }
+    if canister_balance128() < amount {
+        return RefuelOutcome::Failed(RefuelError::OutOfCycles);
+    }
+    let res = deposit_cycles(
+        CanisterIdRecord {
+            canister_id: target.id,
+        },
+        amount,
+    )
+    .await;
+    if let Err((code, msg)) = res {
+        return RefuelOutcome::Failed(classify_refuel_error(code, &msg));
+    }
+    record_cumulative_refueled(target.id, amount);
+    add_observed_cycles(target.id, amount);
+    append_refuel_event(RefuelEvent {
+        timestamp: ic_cdk::api::time(),
+        target: target.id,
+        amount,
+        target_balance_before: balance_u128,
+        vault_balance_after: canister_balance128(),
+        trigger: trigger.clone(),
+    });
+    ic_cdk::println!(
+        "[{}] refueled: {} ",
+        target.id.to_string(),
+        amount.to_string(),
+    );
+    RefuelOutcome::Refueled { amount }
+}
+
+/// Classify the rejection of a call to the management canister
+/// NOTE: The rejection code and the error code of the management canister (e.g. `IC0301`) are checked first.
+///       Messages without a known code are matched by their text, and anything else is `Other`.
+fn classify_refuel_error(code: RejectionCode, msg: &str) -> RefuelError {
+    if code == RejectionCode::DestinationInvalid {
+        return RefuelError::TargetNotFound;
+    }
+    match management_error_code(msg) {
+        Some(301) => return RefuelError::TargetNotFound,
+        Some(512) => return RefuelError::NotController,
+        Some(501) => return RefuelError::OutOfCycles,
+        _ => {}
     }
+    let lower = msg.to_lowercase();
+    if lower.contains("not found") {
+        return RefuelError::TargetNotFound;
+    }
+    if lower.contains("controller") {
+        return RefuelError::NotController;
+    }
+    if lower.contains("out of cycles") || lower.contains("insufficient cycles") {
+        return RefuelError::OutOfCycles;
+    }
+    RefuelError::Other(
+        format!("{:?}: {}", code, msg)
+            .chars()
+            .take(MAX_REFUEL_ERROR_MESSAGE_LEN)
+            .collect(),
+    )
+}
+
+/// Error code of the management canister in the message, e.g. 301 for `IC0301`
+fn management_error_code(msg: &str) -> Option<u32> {
+    msg.match_indices("IC").find_map(|(i, _)| {
+        let digits = msg.get(i + 2..i + 6)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    })
 }
 
 #[update]
 #[candid_method(update)]
-async fn put_refuel_target(target: RefuelTarget) {
-    let res = canister_status(CanisterIdRecord {
-        canister_id: ic_cdk::id(),
-    })
-    .await
-    .unwrap()
-    .0;
-    if !res.settings.controllers.contains(&caller()) {
+fn put_refuel_target(target: RefuelTarget) {
+    assert_role(Role::Admin);
+    _put_refuel_target(&target, ic_cdk::api::time());
+}
+
+fn assert_role(role: Role) {
+    let caller = caller();
+    // NOTE: Controllers are checked by the system API, without calling `canister_status`
+    if !ic_cdk::api::is_controller(&caller) && !has_role(caller, role) {
         panic!("Not permitted")
     }
-    _put_refuel_target(&target);
 }
 
-fn _put_refuel_target(target: &RefuelTarget) {
-    let position = REFUEL_TARGETS.with(|m| m.borrow().iter().position(|s| s.id == target.id));
-    if let Some(i) = position {
-        REFUEL_TARGETS.with(|m| {
-            m.borrow_mut().set(i as u64, &target);
-        })
-    } else {
-        REFUEL_TARGETS.with(|m| {
-            m.borrow_mut().push(&target).unwrap();
-        })
+fn has_role(principal: Principal, role: Role) -> bool {
+    roles_of(principal).has(role)
+}
+
+fn roles_of(principal: Principal) -> Roles {
+    ROLES.with(|m| m.borrow().get(&principal.into()).unwrap_or_default())
+}
+
+#[query]
+#[candid_method(query)]
+fn get_roles(principal: Principal) -> Vec<Role> {
+    roles_of(principal).0
+}
+
+#[query]
+#[candid_method(query)]
+fn get_role_members() -> Vec<(Principal, Vec<Role>)> {
+    ROLES.with(|m| m.borrow().iter().map(|(k, v)| (k.0, v.0)).collect())
+}
+
+#[update]
+#[candid_method(update)]
+fn grant_role(principal: Principal, role: Role) {
+    assert_role(Role::Admin);
+    _grant_role(principal, role);
+}
+
+fn _grant_role(principal: Principal, role: Role) {
+    let mut roles = roles_of(principal);
+    if !roles.0.contains(&role) {
+        roles.0.push(role);
+        roles.0.sort();
     }
+    ROLES.with(|m| m.borrow_mut().insert(principal.into(), roles));
+}
+
+#[update]
+#[candid_method(update)]
+fn revoke_role(principal: Principal, role: Role) {
+    assert_role(Role::Admin);
+    _revoke_role(principal, role);
+}
+
+fn _revoke_role(principal: Principal, role: Role) {
+    let mut roles = roles_of(principal);
+    roles.0.retain(|r| *r != role);
+    ROLES.with(|m| match roles.0.is_empty() {
+        true => m.borrow_mut().remove(&principal.into()),
+        false => m.borrow_mut().insert(principal.into(), roles),
+    });
 }
 
+#[query]
+#[candid_method(query)]
+fn get_access_config() -> AccessConfig {
+    ACCESS_CONFIG.with(|m| m.borrow().get().clone())
+}
+
+#[update]
+#[candid_method(update)]
+fn set_access_config(config: AccessConfig) {
+    assert_role(Role::Admin);
+    let res = ACCESS_CONFIG.with(|m| m.borrow_mut().set(config));
+    res.unwrap(); // todo: use result
+}
+
+/// Add the target or update the existing one keeping its metadata
+fn _put_refuel_target(target: &RefuelTarget, now: u64) {
+    let entry = match refuel_target_entry_of(target.id) {
+        Some(entry) => RefuelTargetEntry {
+            target: *target,
+            ..entry
+        },
+        None => RefuelTargetEntry {
+            target: *target,
+            label: String::new(),
+            created_at: now,
+            is_paused: false,
+            priority: 0,
+        },
+    };
+    REFUEL_TARGET_ENTRIES.with(|m| m.borrow_mut().insert(target.id.into(), entry));
+}
+
+fn refuel_target_entry_of(id: Principal) -> Option<RefuelTargetEntry> {
+    REFUEL_TARGET_ENTRIES.with(|m| m.borrow().get(&id.into()))
+}
+
+fn update_refuel_target_entry(id: Principal, f: impl FnOnce(&mut RefuelTargetEntry)) {
+    let mut entry = refuel_target_entry_of(id).expect("Refuel target not found");
+    f(&mut entry);
+    REFUEL_TARGET_ENTRIES.with(|m| m.borrow_mut().insert(id.into(), entry));
+}
+
+#[update]
+#[candid_method(update)]
+fn remove_refuel_target(id: Principal) {
+    assert_role(Role::Admin);
+    let removed = REFUEL_TARGET_ENTRIES.with(|m| m.borrow_mut().remove(&id.into()));
+    assert!(removed.is_some(), "Refuel target not found");
+}
+
+#[update]
+#[candid_method(update)]
+fn pause_refuel_target(id: Principal) {
+    assert_role(Role::Operator);
+    update_refuel_target_entry(id, |e| e.is_paused = true);
+}
+
+#[update]
+#[candid_method(update)]
+fn resume_refuel_target(id: Principal) {
+    assert_role(Role::Operator);
+    update_refuel_target_entry(id, |e| e.is_paused = false);
+}
+
+#[update]
+#[candid_method(update)]
+fn set_refuel_target_metadata(id: Principal, label: String, priority: u32) {
+    assert_role(Role::Admin);
+    let label = truncate_label(label);
+    update_refuel_target_entry(id, |e| {
+        e.label = label;
+        e.priority = priority;
+    });
+}
+
+/// Truncate the label to at most MAX_REFUEL_TARGET_LABEL_BYTES on a char boundary
+fn truncate_label(mut label: String) -> String {
+    let mut len = label.len().min(MAX_REFUEL_TARGET_LABEL_BYTES);
+    while !label.is_char_boundary(len) {
+        len -= 1;
+    }
+    label.truncate(len);
+    label
+}
+
+/// Targets including paused ones, in the order to be refueled
 #[query]
 #[candid_method(query)]
 fn get_refuel_targets() -> Vec<RefuelTarget> {
-    REFUEL_TARGETS.with(|m| m.borrow().iter().map(|s| s.clone()).collect::<Vec<_>>())
+    refuel_target_entries()
+        .into_iter()
+        .map(|e| e.target)
+        .collect()
+}
+
+#[query]
+#[candid_method(query)]
+fn get_refuel_target_entries() -> Vec<RefuelTargetEntry> {
+    refuel_target_entries()
+}
+
+// NOTE: Sorted by priority, then by id
+fn refuel_target_entries() -> Vec<RefuelTargetEntry> {
+    let mut entries: Vec<RefuelTargetEntry> =
+        REFUEL_TARGET_ENTRIES.with(|m| m.borrow().iter().map(|(_, v)| v).collect());
+    entries.sort_by(|a, b| {
+        b.priority
+            .cmp(&a.priority)
+            .then(a.target.id.cmp(&b.target.id))
+    });
+    entries
+}
+
+/// Move the targets in the legacy vector to the keyed map, the vector is emptied
+fn migrate_refuel_targets(now: u64) {
+    let legacy: Vec<LegacyRefuelTarget> = REFUEL_TARGETS.with(|m| m.borrow().iter().collect());
+    for target in legacy.into_iter().map(RefuelTarget::from) {
+        if refuel_target_entry_of(target.id).is_none() {
+            _put_refuel_target(&target, now);
+        }
+    }
+    REFUEL_TARGETS.with(|m| {
+        let v = m.borrow_mut();
+        while v.pop().is_some() {}
+    });
 }
 
 #[update]
@@ -341,6 +1497,7 @@ fn target_canister() -> Principal {
 #[update]
 #[candid_method(update)]
 fn set_canister(principal: Principal) {
+    assert_role(Role::Admin);
     _set_target_canister(principal);
 }
 
@@ -349,10 +1506,106 @@ fn _set_target_canister(principal: Principal) {
     res.unwrap(); // todo: use result
 }
 
+fn update_burn_rate(
+    target: Principal,
+    cycles: u128,
+    idle_cycles_per_day: u128,
+    now: u64,
+) -> BurnRate {
+    let measured = observed_cycles_of(target)
+        .and_then(|prev| predictive::measured_burn_per_day(&prev, cycles, now));
+    let rate = predictive::next_burn_rate(
+        get_burn_rate(target).as_ref(),
+        measured,
+        idle_cycles_per_day,
+        now,
+    );
+    BURN_RATES.with(|m| m.borrow_mut().insert(target.into(), rate.clone()));
+    rate
+}
+
+#[query]
+#[candid_method(query)]
+fn get_burn_rate(target: Principal) -> Option<BurnRate> {
+    BURN_RATES.with(|m| m.borrow().get(&target.into()))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_predictive_refuel_config() -> PredictiveRefuelConfig {
+    PREDICTIVE_REFUEL_CONFIG.with(|m| m.borrow().get().clone())
+}
+
+#[update]
+#[candid_method(update)]
+fn set_predictive_refuel_config(config: PredictiveRefuelConfig) {
+    assert_role(Role::Admin);
+    let res = PREDICTIVE_REFUEL_CONFIG.with(|m| m.borrow_mut().set(config));
+    res.unwrap(); // todo: use result
+}
+
+/// Seconds until the earliest target is predicted to need refueling
+/// NOTE: The prediction starts from the last observation, so the time since then is subtracted
+fn next_predicted_refuel_secs(now: u64) -> Option<u64> {
+    let config = get_predictive_refuel_config();
+    if !config.is_enabled {
+        return None;
+    }
+    refuel_target_entries()
+        .into_iter()
+        .filter(|e| !e.is_paused)
+        .filter_map(|e| {
+            let rate = get_burn_rate(e.target.id)?;
+            let observation = observed_cycles_of(e.target.id)?;
+            let required =
+                predictive::required_balance(e.target.threshold, &rate, config.runway_secs);
+            let secs = predictive::secs_until_required(observation.cycles, required, &rate)?;
+            let age_secs = now.saturating_sub(observation.timestamp) / (1000 * 1000000);
+            Some(secs.saturating_sub(age_secs))
+        })
+        .min()
+}
+
+/// Schedule a refuel just in time, in addition to the fixed interval
+/// NOTE: The timer is on the heap, so it is scheduled again in post_upgrade
+fn schedule_predicted_refuel() {
+    if let Some(timer_id) = PREDICTED_REFUEL_TIMER_ID.with(|t| t.borrow_mut().take()) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+    let Some(secs) = next_predicted_refuel_secs(ic_cdk::api::time()) else {
+        return;
+    };
+    let delay = secs.max(MIN_PREDICTED_REFUEL_DELAY_SECS);
+    let timer_id = ic_cdk_timers::set_timer(Duration::from_secs(delay), || {
+        PREDICTED_REFUEL_TIMER_ID.with(|t| *t.borrow_mut() = None);
+        ic_cdk::spawn(_refuel(RefuelTrigger::Predicted))
+    });
+    PREDICTED_REFUEL_TIMER_ID.with(|t| *t.borrow_mut() = Some(timer_id));
+}
+
+fn refueling_interval_secs() -> u64 {
+    REFUELING_INTERVAL_SECS.with(|m| *m.borrow().get())
+}
+
+/// Change the interval of the scheduled refuel, also to restart the refueling of vaults installed before it was stored
+#[update]
+#[candid_method(update)]
+fn set_refueling_interval(interval_secs: u64) {
+    assert_role(Role::Admin);
+    assert!(interval_secs > 0, "Interval must be positive");
+    start_refueling(interval_secs);
+}
+
 fn start_refueling(interval_secs: u64) {
-    ic_cdk_timers::set_timer_interval(Duration::from_secs(interval_secs), || {
-        ic_cdk::spawn(refuel())
+    if let Some(timer_id) = REFUELING_TIMER_ID.with(|t| t.borrow_mut().take()) {
+        ic_cdk_timers::clear_timer(timer_id);
+    }
+    let res = REFUELING_INTERVAL_SECS.with(|m| m.borrow_mut().set(interval_secs));
+    res.unwrap(); // todo: use result
+    let timer_id = ic_cdk_timers::set_timer_interval(Duration::from_secs(interval_secs), || {
+        ic_cdk::spawn(_refuel(RefuelTrigger::Scheduled))
     });
+    REFUELING_TIMER_ID.with(|t| *t.borrow_mut() = Some(timer_id));
 }
 
 #[ic_cdk::query]
@@ -437,6 +1690,103 @@ fn record_cumulative_refueled(target: Principal, amount: u128) {
     })
 }
 
+#[query]
+#[candid_method(query)]
+fn get_last_refuel_result(target: Principal) -> Option<RefuelResult> {
+    LAST_REFUEL_RESULTS.with(|m| m.borrow().get(&target.into()))
+}
+
+#[query]
+#[candid_method(query)]
+fn get_last_refuel_result_all() -> Vec<(Principal, RefuelResult)> {
+    LAST_REFUEL_RESULTS.with(|m| m.borrow().iter().map(|(k, v)| (k.0, v)).collect())
+}
+
+fn record_refuel_result(target: Principal, result: RefuelResult) {
+    LAST_REFUEL_RESULTS.with(|m| m.borrow_mut().insert(target.into(), result));
+}
+
+fn append_refuel_event(event: RefuelEvent) {
+    REFUEL_EVENTS.with(|m| {
+        let mut events = m.borrow_mut();
+        let next = events.len();
+        events.insert(next, event);
+    });
+}
+
+#[query]
+#[candid_method(query)]
+fn get_refuel_events_count() -> u64 {
+    REFUEL_EVENTS.with(|m| m.borrow().len())
+}
+
+/// Refuel events from the oldest, `limit` is capped by `MAX_REFUEL_EVENTS_PAGE_SIZE`
+#[query]
+#[candid_method(query)]
+fn get_refuel_events(offset: u64, limit: u64) -> Vec<(u64, RefuelEvent)> {
+    let limit = limit.min(MAX_REFUEL_EVENTS_PAGE_SIZE);
+    REFUEL_EVENTS.with(|m| m.borrow().range(offset..).take(limit as usize).collect())
+}
+
+/// Refuel events with timestamps in `[from, to)` from the oldest, continue from the last id with `get_refuel_events`
+#[query]
+#[candid_method(query)]
+fn get_refuel_events_between(from: u64, to: u64, limit: u64) -> Vec<(u64, RefuelEvent)> {
+    let limit = limit.min(MAX_REFUEL_EVENTS_PAGE_SIZE);
+    let first = first_refuel_event_at_or_after(from);
+    REFUEL_EVENTS.with(|m| {
+        m.borrow()
+            .range(first..)
+            .take_while(|(_, e)| e.timestamp < to)
+            .take(limit as usize)
+            .collect()
+    })
+}
+
+fn first_refuel_event_at_or_after(timestamp: u64) -> u64 {
+    REFUEL_EVENTS.with(|m| {
+        let events = m.borrow();
+        let (mut low, mut high) = (0, events.len());
+        while low < high {
+            let mid = low + (high - low) / 2;
+            if events.get(&mid).unwrap().timestamp < timestamp {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+        low
+    })
+}
+
+/// Cycles balance of the canister as of the last refueling, used by the proxy to skip underfunded runs
+#[query]
+#[candid_method(query)]
+fn observed_cycles_of(target: Principal) -> Option<CycleObservation> {
+    OBSERVED_CYCLES.with(|m| m.borrow().get(&target.into()))
+}
+
+fn record_observed_cycles(target: Principal, cycles: u128) {
+    let timestamp = ic_cdk::api::time();
+    _record_observed_cycles(target, CycleObservation { cycles, timestamp });
+}
+
+fn _record_observed_cycles(target: Principal, observation: CycleObservation) {
+    OBSERVED_CYCLES.with(|m| m.borrow_mut().insert(target.into(), observation));
+}
+
+fn add_observed_cycles(target: Principal, amount: u128) {
+    if let Some(observation) = observed_cycles_of(target) {
+        _record_observed_cycles(
+            target,
+            CycleObservation {
+                cycles: observation.cycles.saturating_add(amount),
+                ..observation
+            },
+        );
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -498,14 +1848,634 @@ mod tests {
         assert_eq!(balance_of(depositor2), Balance::from(150));
     }
 
+    #[test]
+    fn test_withdrawal() {
+        let depositor1 = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let depositor2 = Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap();
+        increase_index(&1_000.into(), depositor1);
+        increase_index(&500.into(), depositor2);
+
+        // reserved shares are not available until restored
+        let id = reserve_withdrawal(depositor1, &400.into(), 1);
+        assert_eq!(balance_of(depositor1), Balance::from(600));
+        assert_eq!(total_supply(), Balance::from(1_100));
+        assert_eq!(
+            pending_withdrawals(),
+            vec![(
+                id,
+                PendingWithdrawal {
+                    principal: depositor1,
+                    amount: 400.into(),
+                    share: Index::from(400),
+                    created_at: 1,
+                }
+            )]
+        );
+        restore_withdrawal(id);
+        assert_eq!(balance_of(depositor1), Balance::from(1_000));
+        assert_eq!(share_of(depositor1), Index::from(1_000));
+        assert_eq!(index(), Index::from(1_500));
+        assert_eq!(total_supply(), Balance::from(1_500));
+        assert!(pending_withdrawals().is_empty());
+
+        // committed
+        let id = reserve_withdrawal(depositor2, &200.into(), 2);
+        commit_withdrawal(id);
+        assert_eq!(balance_of(depositor2), Balance::from(300));
+        assert_eq!(total_supply(), Balance::from(1_300));
+        assert!(pending_withdrawals().is_empty());
+
+        // resolved by the admin
+        let id1 = reserve_withdrawal(depositor1, &100.into(), 3);
+        let id2 = reserve_withdrawal(depositor2, &100.into(), 3);
+        _resolve_pending_withdrawal(id1, true);
+        _resolve_pending_withdrawal(id2, false);
+        assert_eq!(balance_of(depositor1), Balance::from(900));
+        assert_eq!(balance_of(depositor2), Balance::from(300));
+        assert!(pending_withdrawals().is_empty());
+
+        // restored on upgrades
+        reserve_withdrawal(depositor1, &100.into(), 4);
+        reserve_withdrawal(depositor2, &50.into(), 4);
+        reconcile_pending_withdrawals();
+        assert_eq!(balance_of(depositor1), Balance::from(900));
+        assert_eq!(balance_of(depositor2), Balance::from(300));
+        assert_eq!(total_supply(), Balance::from(1_200));
+        assert!(pending_withdrawals().is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Pending withdrawal not found")]
+    fn test_resolve_unknown_pending_withdrawal() {
+        _resolve_pending_withdrawal(0, false);
+    }
+
+    #[test]
+    fn test_transfer_share() {
+        let depositor1 = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let depositor2 = Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap();
+        increase_index(&1_000.into(), depositor1);
+        add_total_supply(&1_000.into(), false);
+
+        // in balance
+        let id =
+            _transfer_share(depositor1, depositor2, ShareAmount::Balance(500.into()), 1).unwrap();
+        assert_eq!(id, 0);
+        assert_eq!(share_of(depositor1), Index::from(750));
+        assert_eq!(share_of(depositor2), Index::from(250));
+        assert_eq!(balance_of(depositor2), Balance::from(500));
+        assert_eq!(index(), Index::from(1_000));
+        assert_eq!(total_supply(), Balance::from(2_000));
+
+        // in share
+        assert_eq!(
+            _transfer_share(
+                depositor2,
+                depositor1,
+                ShareAmount::Share(Index::from(300)),
+                2
+            ),
+            Err(TransferShareError::InsufficientShare {
+                available: Index::from(250)
+            })
+        );
+        _transfer_share(
+            depositor2,
+            depositor1,
+            ShareAmount::Share(Index::from(250)),
+            3,
+        )
+        .unwrap();
+        assert_eq!(share_of(depositor1), Index::from(1_000));
+        assert_eq!(share_of(depositor2), Index::default());
+
+        assert_eq!(
+            _transfer_share(depositor1, depositor1, ShareAmount::Balance(1.into()), 4),
+            Err(TransferShareError::SelfTransfer)
+        );
+        assert_eq!(
+            _transfer_share(depositor1, depositor2, ShareAmount::Balance(1.into()), 4),
+            Err(TransferShareError::ZeroAmount)
+        );
+        assert_eq!(get_share_transfers_count(), 2);
+        assert_eq!(
+            get_share_transfers(1, 10),
+            vec![(
+                1,
+                ShareTransfer {
+                    timestamp: 3,
+                    from: depositor2,
+                    to: depositor1,
+                    share: Index::from(250),
+                    amount: 500.into(),
+                    spender: None,
+                    memo: None,
+                }
+            )]
+        );
+    }
+
+    #[test]
+    fn test_icrc() {
+        let owner = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let spender = Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap();
+        let receiver = Principal::anonymous();
+        increase_index(&1_000.into(), owner);
+        add_total_supply(&1_000.into(), false);
+        assert_eq!(icrc1_total_supply(), Nat::from(1_000u32));
+        assert_eq!(icrc1_balance_of(owner.into()), Nat::from(1_000u32));
+
+        // transfer
+        let arg = TransferArg {
+            from_subaccount: None,
+            to: spender.into(),
+            amount: Nat::from(100u32),
+            fee: None,
+            memo: Some(vec![1]),
+            created_at_time: Some(10),
+        };
+        assert_eq!(_icrc1_transfer(owner, arg.clone(), 10), Ok(Nat::from(0u8)));
+        assert_eq!(icrc1_balance_of(spender.into()), Nat::from(100u32));
+        // retried in the window
+        assert_eq!(
+            _icrc1_transfer(owner, arg.clone(), 20),
+            Err(TransferError::Duplicate {
+                duplicate_of: Nat::from(0u8)
+            })
+        );
+        assert_eq!(icrc1_balance_of(spender.into()), Nat::from(100u32));
+        // transactions without `created_at_time` are not deduplicated
+        let untimed = TransferArg {
+            amount: Nat::from(1u8),
+            created_at_time: None,
+            ..arg.clone()
+        };
+        assert_eq!(
+            _icrc1_transfer(owner, untimed.clone(), 20),
+            Ok(Nat::from(1u8))
+        );
+        assert_eq!(
+            _icrc1_transfer(
+                spender,
+                TransferArg {
+                    to: owner.into(),
+                    ..untimed
+                },
+                20
+            ),
+            Ok(Nat::from(2u8))
+        );
+        assert_eq!(
+            _icrc1_transfer(
+                spender,
+                TransferArg {
+                    to: owner.into(),
+                    amount: Nat::from(101u32),
+                    ..arg.clone()
+                },
+                10
+            ),
+            Err(TransferError::InsufficientFunds {
+                balance: Nat::from(100u32)
+            })
+        );
+        assert!(matches!(
+            _icrc1_transfer(
+                owner,
+                TransferArg {
+                    to: Account {
+                        owner: spender,
+                        subaccount: Some(vec![1; 32]),
+                    },
+                    ..arg.clone()
+                },
+                10
+            ),
+            Err(TransferError::GenericError { .. })
+        ));
+
+        // approve
+        let approve = ApproveArgs {
+            from_subaccount: None,
+            spender: spender.into(),
+            amount: Nat::from(300u32),
+            expected_allowance: Some(Nat::from(0u8)),
+            expires_at: Some(100),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        // indexed apart from the transfers
+        assert_eq!(
+            _icrc2_approve(owner, approve.clone(), 10),
+            Ok(Nat::from(0u8))
+        );
+        assert_eq!(get_share_approvals(0, 10)[0].1.share, Index::from(300));
+        assert_eq!(
+            _icrc2_approve(owner, approve.clone(), 10),
+            Err(ApproveError::AllowanceChanged {
+                current_allowance: Nat::from(300u32)
+            })
+        );
+        let allowance_args = AllowanceArgs {
+            account: owner.into(),
+            spender: spender.into(),
+        };
+        assert_eq!(
+            _icrc2_allowance(allowance_args.clone(), 10),
+            Allowance {
+                allowance: Nat::from(300u32),
+                expires_at: Some(100),
+            }
+        );
+
+        // transfer from
+        let transfer_from = TransferFromArgs {
+            spender_subaccount: None,
+            from: owner.into(),
+            to: receiver.into(),
+            amount: Nat::from(200u32),
+            fee: None,
+            memo: None,
+            created_at_time: None,
+        };
+        assert_eq!(
+            _icrc2_transfer_from(spender, transfer_from.clone(), 20),
+            Ok(Nat::from(3u8))
+        );
+        assert_eq!(icrc1_balance_of(owner.into()), Nat::from(700u32));
+        assert_eq!(icrc1_balance_of(receiver.into()), Nat::from(200u32));
+        assert_eq!(
+            _icrc2_allowance(allowance_args.clone(), 20).allowance,
+            Nat::from(100u32)
+        );
+        assert_eq!(get_share_transfers(3, 1)[0].1.spender, Some(spender));
+        assert_eq!(
+            _icrc2_transfer_from(spender, transfer_from.clone(), 20),
+            Err(TransferFromError::InsufficientAllowance {
+                allowance: Nat::from(100u32)
+            })
+        );
+        // expired
+        assert_eq!(
+            _icrc2_allowance(allowance_args, 100).allowance,
+            Nat::from(0u8)
+        );
+        assert_eq!(
+            _icrc2_transfer_from(
+                spender,
+                TransferFromArgs {
+                    amount: Nat::from(50u32),
+                    ..transfer_from
+                },
+                100
+            ),
+            Err(TransferFromError::InsufficientAllowance {
+                allowance: Nat::from(0u8)
+            })
+        );
+
+        // approvals with `created_at_time` are deduplicated too
+        let timed = ApproveArgs {
+            amount: Nat::from(50u32),
+            expected_allowance: None,
+            expires_at: None,
+            created_at_time: Some(100),
+            ..approve
+        };
+        assert_eq!(
+            _icrc2_approve(owner, timed.clone(), 100),
+            Ok(Nat::from(1u8))
+        );
+        assert_eq!(
+            _icrc2_approve(owner, timed, 100),
+            Err(ApproveError::Duplicate {
+                duplicate_of: Nat::from(1u8)
+            })
+        );
+        assert_eq!(get_share_approvals_count(), 2);
+
+        // pruned out of the window, where retries are rejected as too old
+        let day = 24 * 60 * 60 * 1_000_000_000;
+        assert_eq!(
+            _icrc1_transfer(owner, arg.clone(), 2 * day),
+            Err(TransferError::TooOld)
+        );
+        let later = TransferArg {
+            created_at_time: Some(2 * day),
+            ..arg
+        };
+        assert_eq!(_icrc1_transfer(owner, later, 2 * day), Ok(Nat::from(4u8)));
+        assert_eq!(RECENT_TRANSACTIONS.with(|m| m.borrow().len()), 1);
+        assert_eq!(RECENT_TRANSACTION_ORDER.with(|m| m.borrow().len()), 1);
+
+        // supply and withdraw still mint and burn
+        increase_index(&2_000.into(), receiver);
+        assert_eq!(icrc1_total_supply(), Nat::from(2_000u32));
+        assert_eq!(icrc1_balance_of(receiver.into()), Nat::from(1_200u32));
+    }
+
+    /// Local stand-in of the ICP ledger holding the balance of a single deposit account
+    struct LocalLedger {
+        balance_e8s: RefCell<u64>,
+        transfers: RefCell<Vec<TransferArg>>,
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl IcpLedger for LocalLedger {
+        async fn icrc1_balance_of(&self, _: Account) -> ic_cdk::api::call::CallResult<Nat> {
+            Ok(Nat::from(*self.balance_e8s.borrow()))
+        }
+        async fn icrc1_transfer(
+            &self,
+            arg: TransferArg,
+        ) -> ic_cdk::api::call::CallResult<Result<Nat, TransferError>> {
+            let debit = icrc::to_u128(&arg.amount).unwrap() as u64 + icp::ICP_FEE_E8S;
+            let balance = *self.balance_e8s.borrow();
+            if balance < debit {
+                return Ok(Err(TransferError::InsufficientFunds {
+                    balance: Nat::from(balance),
+                }));
+            }
+            *self.balance_e8s.borrow_mut() -= debit;
+            self.transfers.borrow_mut().push(arg);
+            Ok(Ok(Nat::from(self.transfers.borrow().len() as u64 + 100)))
+        }
+    }
+
+    /// Local stand-in of the CMC minting 10_000 cycles per e8s unless it fails
+    /// NOTE: Like the CMC, it answers every notification of the same block
+    struct LocalCmc {
+        ledger_transfers: Vec<TransferArg>,
+        error: RefCell<Option<NotifyError>>,
+        notified: RefCell<Vec<u64>>,
+        /// Yield once before answering, to interleave another notification
+        is_yielding: bool,
+    }
+    impl LocalCmc {
+        fn new(ledger_transfers: Vec<TransferArg>, error: Option<NotifyError>) -> Self {
+            Self {
+                ledger_transfers,
+                error: RefCell::new(error),
+                notified: RefCell::new(vec![]),
+                is_yielding: false,
+            }
+        }
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl CyclesMinting for LocalCmc {
+        async fn notify_top_up(
+            &self,
+            arg: NotifyTopUpArg,
+        ) -> ic_cdk::api::call::CallResult<NotifyTopUpResult> {
+            self.notified.borrow_mut().push(arg.block_index);
+            if self.is_yielding {
+                let mut is_yielded = false;
+                futures::future::poll_fn(|cx| match std::mem::replace(&mut is_yielded, true) {
+                    true => std::task::Poll::Ready(()),
+                    false => {
+                        cx.waker().wake_by_ref();
+                        std::task::Poll::Pending
+                    }
+                })
+                .await;
+            }
+            if let Some(err) = self.error.borrow_mut().take() {
+                return Ok(NotifyTopUpResult::Err(err));
+            }
+            let amount = self
+                .ledger_transfers
+                .get((arg.block_index - 101) as usize)
+                .map(|t| icrc::to_u128(&t.amount).unwrap())
+                .unwrap();
+            Ok(NotifyTopUpResult::Ok(Nat::from(amount * 10_000)))
+        }
+    }
+
+    #[test]
+    fn test_notify_icp_deposit() {
+        let vault = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let depositor = Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap();
+        let ledger = LocalLedger {
+            balance_e8s: RefCell::new(1_010_000),
+            transfers: RefCell::new(vec![]),
+        };
+        let notify = |cmc: &LocalCmc, top_up_block: Option<u64>| {
+            futures::executor::block_on(_notify_icp_deposit(
+                &ledger,
+                cmc,
+                vault,
+                depositor,
+                top_up_block,
+                1,
+            ))
+        };
+
+        // the CMC is unavailable, the deposit is kept at the CMC
+        let failing = LocalCmc::new(vec![], Some(NotifyError::Processing));
+        assert!(matches!(
+            notify(&failing, None),
+            Err(IcpDepositError::NotifyFailed(_))
+        ));
+        assert_eq!(*ledger.balance_e8s.borrow(), 0);
+        assert_eq!(
+            get_icp_deposits(depositor),
+            vec![(
+                101,
+                IcpDeposit {
+                    depositor,
+                    notified_at: 1,
+                    status: IcpDepositStatus::Transferred {
+                        top_up_block: 101,
+                        amount_e8s: 1_000_000,
+                    },
+                }
+            )]
+        );
+        let transfer = ledger.transfers.borrow()[0].clone();
+        assert_eq!(
+            transfer.from_subaccount,
+            icp_deposit_account_of(vault, depositor)
+        );
+        assert_eq!(
+            transfer.to.subaccount,
+            Some(icp::principal_to_subaccount(vault))
+        );
+
+        // blocks not transferred by the vault for the caller are unknown
+        let cmc = LocalCmc::new(ledger.transfers.borrow().clone(), None);
+        assert_eq!(notify(&cmc, Some(7)), Err(IcpDepositError::NotFound));
+        assert_eq!(
+            futures::executor::block_on(_notify_icp_deposit(
+                &ledger,
+                &cmc,
+                vault,
+                Principal::anonymous(),
+                Some(101),
+                1,
+            )),
+            Err(IcpDepositError::NotFound)
+        );
+        assert!(cmc.notified.borrow().is_empty());
+
+        // retried without transferring again
+        assert_eq!(notify(&cmc, Some(101)), Ok(10_000_000_000));
+        assert_eq!(ledger.transfers.borrow().len(), 1);
+        assert_eq!(*cmc.notified.borrow(), vec![101]);
+        assert_eq!(balance_of(depositor), Balance::from(10_000_000_000));
+        assert_eq!(total_supply(), Balance::from(10_000_000_000));
+
+        // notified twice
+        assert_eq!(
+            notify(&cmc, Some(101)),
+            Err(IcpDepositError::AlreadyCompleted {
+                cycles: 10_000_000_000
+            })
+        );
+        assert_eq!(cmc.notified.borrow().len(), 1);
+        assert_eq!(balance_of(depositor), Balance::from(10_000_000_000));
+
+        // nothing deposited
+        assert_eq!(
+            notify(&cmc, None),
+            Err(IcpDepositError::InsufficientDeposit { balance_e8s: 0 })
+        );
+        assert_eq!(get_icp_deposits(depositor).len(), 1);
+
+        // refunded by the CMC, the deposit is forgotten
+        *ledger.balance_e8s.borrow_mut() = 20_000;
+        let refunding = LocalCmc::new(
+            vec![],
+            Some(NotifyError::Refunded {
+                block_index: None,
+                reason: "refunded".to_string(),
+            }),
+        );
+        assert_eq!(
+            notify(&refunding, None),
+            Err(IcpDepositError::Refunded("refunded".to_string()))
+        );
+        assert_eq!(icp_deposit_of(depositor, 102), None);
+    }
+
+    #[test]
+    fn test_notify_icp_deposit_concurrently() {
+        let vault = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let depositor = Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap();
+        let ledger = LocalLedger {
+            balance_e8s: RefCell::new(1_010_000),
+            transfers: RefCell::new(vec![]),
+        };
+        let failing = LocalCmc::new(vec![], Some(NotifyError::Processing));
+        let res = futures::executor::block_on(_notify_icp_deposit(
+            &ledger, &failing, vault, depositor, None, 1,
+        ));
+        assert!(matches!(res, Err(IcpDepositError::NotifyFailed(_))));
+
+        // the block is notified again while the CMC is answering the first notification
+        let cmc = LocalCmc {
+            is_yielding: true,
+            ..LocalCmc::new(ledger.transfers.borrow().clone(), None)
+        };
+        let (first, second) = futures::executor::block_on(futures::future::join(
+            _notify_icp_deposit(&ledger, &cmc, vault, depositor, Some(101), 2),
+            _notify_icp_deposit(&ledger, &cmc, vault, depositor, Some(101), 2),
+        ));
+        assert_eq!(first, Ok(10_000_000_000));
+        assert_eq!(second, Err(IcpDepositError::Processing));
+        assert_eq!(*cmc.notified.borrow(), vec![101]);
+        assert_eq!(balance_of(depositor), Balance::from(10_000_000_000));
+        assert_eq!(
+            icp_deposit_of(depositor, 101).map(|d| d.status),
+            Some(IcpDepositStatus::Completed {
+                top_up_block: 101,
+                cycles: 10_000_000_000,
+            })
+        );
+
+        // notified again after completion, the CMC is not asked again
+        assert_eq!(
+            futures::executor::block_on(_notify_icp_deposit(
+                &ledger,
+                &cmc,
+                vault,
+                depositor,
+                Some(101),
+                3,
+            )),
+            Err(IcpDepositError::AlreadyCompleted {
+                cycles: 10_000_000_000
+            })
+        );
+        assert_eq!(cmc.notified.borrow().len(), 1);
+        assert_eq!(balance_of(depositor), Balance::from(10_000_000_000));
+    }
+
+    #[test]
+    fn test_notify_stuck_icp_deposit() {
+        let vault = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let depositor = Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap();
+        let ledger = LocalLedger {
+            balance_e8s: RefCell::new(1_010_000),
+            transfers: RefCell::new(vec![]),
+        };
+        let failing = LocalCmc::new(vec![], Some(NotifyError::Processing));
+        let res = futures::executor::block_on(_notify_icp_deposit(
+            &ledger, &failing, vault, depositor, None, 1,
+        ));
+        assert!(matches!(res, Err(IcpDepositError::NotifyFailed(_))));
+
+        // the callback of the notification trapped, leaving the deposit marked
+        let deposit = icp_deposit_of(depositor, 101).unwrap();
+        put_icp_deposit(
+            depositor,
+            101,
+            IcpDeposit {
+                status: IcpDepositStatus::Notifying {
+                    top_up_block: 101,
+                    amount_e8s: 1_000_000,
+                    since: 2,
+                },
+                ..deposit
+            },
+        );
+        let cmc = LocalCmc::new(ledger.transfers.borrow().clone(), None);
+        let timeout = icp::NOTIFY_IN_FLIGHT_TIMEOUT_SECS * 1000 * 1000000;
+        let notify = |now: u64| {
+            futures::executor::block_on(_notify_icp_deposit(
+                &ledger,
+                &cmc,
+                vault,
+                depositor,
+                Some(101),
+                now,
+            ))
+        };
+        assert_eq!(notify(2 + timeout - 1), Err(IcpDepositError::Processing));
+        assert!(cmc.notified.borrow().is_empty());
+
+        // notified again after the timeout
+        assert_eq!(notify(2 + timeout), Ok(10_000_000_000));
+        assert_eq!(*cmc.notified.borrow(), vec![101]);
+        assert_eq!(balance_of(depositor), Balance::from(10_000_000_000));
+    }
+
+    fn icp_deposit_account_of(vault: Principal, depositor: Principal) -> Option<Vec<u8>> {
+        icp::deposit_account(vault, depositor).subaccount
+    }
+
     #[test]
     fn test_put_refuel_target() {
         let mut target1 = RefuelTarget {
             id: Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap(),
             threshold: 100,
             amount: 200,
+            strategy: None,
+            min_deposit: None,
+            max_deposit: None,
         };
-        _put_refuel_target(&target1);
+        _put_refuel_target(&target1, 0);
         assert_eq!(get_refuel_targets()[0], target1);
         assert_eq!(get_refuel_targets().len(), 1);
 
@@ -513,17 +2483,113 @@ mod tests {
             id: Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap(),
             threshold: 1000,
             amount: 2000,
+            strategy: None,
+            min_deposit: None,
+            max_deposit: None,
         };
-        _put_refuel_target(&target2);
+        _put_refuel_target(&target2, 0);
         assert_eq!(get_refuel_targets()[1], target2);
         assert_eq!(get_refuel_targets().len(), 2);
 
         target1.amount = 300;
-        _put_refuel_target(&target1);
+        _put_refuel_target(&target1, 0);
         assert_eq!(get_refuel_targets()[0].amount, 300);
         assert_eq!(get_refuel_targets().len(), 2);
     }
 
+    #[test]
+    fn test_refuel_target_entries() {
+        let target1 = RefuelTarget {
+            id: Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap(),
+            threshold: 100,
+            amount: 200,
+            strategy: None,
+            min_deposit: None,
+            max_deposit: None,
+        };
+        let target2 = RefuelTarget {
+            id: Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap(),
+            threshold: 1000,
+            amount: 2000,
+            strategy: None,
+            min_deposit: None,
+            max_deposit: None,
+        };
+        _put_refuel_target(&target1, 1);
+        _put_refuel_target(&target2, 2);
+
+        update_refuel_target_entry(target2.id, |e| {
+            e.label = "db".to_string();
+            e.priority = 10;
+            e.is_paused = true;
+        });
+        assert_eq!(get_refuel_targets(), vec![target2, target1]);
+
+        // metadata is kept on updates
+        _put_refuel_target(
+            &RefuelTarget {
+                amount: 1,
+                ..target2
+            },
+            3,
+        );
+        assert_eq!(
+            refuel_target_entry_of(target2.id),
+            Some(RefuelTargetEntry {
+                target: RefuelTarget {
+                    amount: 1,
+                    ..target2
+                },
+                label: "db".to_string(),
+                created_at: 2,
+                is_paused: true,
+                priority: 10,
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncate_label() {
+        assert_eq!(truncate_label("db".to_string()), "db");
+        assert_eq!(truncate_label("a".repeat(65)), "a".repeat(64));
+        // 4-byte chars are not split
+        assert_eq!(
+            truncate_label("\u{10000}".repeat(64)),
+            "\u{10000}".repeat(16)
+        );
+        assert_eq!(
+            truncate_label(format!("a{}", "\u{10000}".repeat(16))).len(),
+            61
+        );
+    }
+
+    #[test]
+    fn test_migrate_refuel_targets() {
+        let legacy1 = LegacyRefuelTarget {
+            id: Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap(),
+            threshold: 100,
+            amount: 200,
+        };
+        let legacy2 = LegacyRefuelTarget {
+            id: Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap(),
+            threshold: 1000,
+            amount: 2000,
+        };
+        let (target1, target2) = (RefuelTarget::from(legacy1), RefuelTarget::from(legacy2));
+        REFUEL_TARGETS.with(|m| {
+            m.borrow_mut().push(&legacy1).unwrap();
+            m.borrow_mut().push(&legacy2).unwrap();
+        });
+        migrate_refuel_targets(5);
+        assert_eq!(get_refuel_targets(), vec![target1, target2]);
+        assert_eq!(refuel_target_entry_of(target1.id).unwrap().created_at, 5);
+        assert_eq!(REFUEL_TARGETS.with(|m| m.borrow().len()), 0);
+
+        // idempotent
+        migrate_refuel_targets(6);
+        assert_eq!(get_refuel_targets().len(), 2);
+    }
+
     #[test]
     fn test_metrics() {
         let snap1 = ComponentMetricsSnapshot {
@@ -556,6 +2622,253 @@ mod tests {
 
     }
 
+    #[test]
+    fn test_observed_cycles() {
+        let target = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        assert_eq!(observed_cycles_of(target), None);
+
+        // not observed yet
+        add_observed_cycles(target, 100);
+        assert_eq!(observed_cycles_of(target), None);
+
+        _record_observed_cycles(
+            target,
+            CycleObservation {
+                cycles: 1_000,
+                timestamp: 1,
+            },
+        );
+        add_observed_cycles(target, 100);
+        assert_eq!(
+            observed_cycles_of(target),
+            Some(CycleObservation {
+                cycles: 1_100,
+                timestamp: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_classify_refuel_error() {
+        assert_eq!(
+            classify_refuel_error(
+                RejectionCode::DestinationInvalid,
+                "Canister vvqfh-4aaaa-aaaao-a2mua-cai not found"
+            ),
+            RefuelError::TargetNotFound
+        );
+        assert_eq!(
+            classify_refuel_error(
+                RejectionCode::CanisterError,
+                "Only the controllers of the canister vvqfh-4aaaa-aaaao-a2mua-cai can control it."
+            ),
+            RefuelError::NotController
+        );
+        assert_eq!(
+            classify_refuel_error(RejectionCode::CanisterError, "Canister is out of cycles"),
+            RefuelError::OutOfCycles
+        );
+
+        // by the error code of the management canister, whatever the message
+        assert_eq!(
+            classify_refuel_error(RejectionCode::CanisterReject, "IC0301: Canister is gone"),
+            RefuelError::TargetNotFound
+        );
+        assert_eq!(
+            classify_refuel_error(
+                RejectionCode::CanisterReject,
+                "Error from Canister aaaaa-aa: IC0512"
+            ),
+            RefuelError::NotController
+        );
+        assert_eq!(
+            classify_refuel_error(
+                RejectionCode::CanisterError,
+                "IC0501: Canister has no funds"
+            ),
+            RefuelError::OutOfCycles
+        );
+        assert_eq!(
+            classify_refuel_error(
+                RejectionCode::CanisterReject,
+                "IC0503: Canister called trap"
+            ),
+            RefuelError::Other("CanisterReject: IC0503: Canister called trap".to_string())
+        );
+        assert_eq!(management_error_code("IC05"), None);
+        assert_eq!(management_error_code("ICP IC0301"), Some(301));
+        let err = classify_refuel_error(RejectionCode::SysTransient, &"x".repeat(10_000));
+        let RefuelError::Other(msg) = &err else {
+            panic!("unexpected: {:?}", err);
+        };
+        assert_eq!(msg.len(), MAX_REFUEL_ERROR_MESSAGE_LEN);
+
+        use ic_stable_structures::{BoundedStorable, Storable};
+        let result = RefuelResult {
+            timestamp: u64::MAX,
+            outcome: RefuelOutcome::Failed(err),
+        };
+        assert!(result.to_bytes().len() as u32 <= RefuelResult::MAX_SIZE);
+    }
+
+    #[test]
+    fn test_last_refuel_result() {
+        let target = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        assert_eq!(get_last_refuel_result(target), None);
+        let result = RefuelResult {
+            timestamp: 1,
+            outcome: RefuelOutcome::Refueled { amount: 100 },
+        };
+        record_refuel_result(target, result.clone());
+        let result = RefuelResult {
+            timestamp: 2,
+            outcome: RefuelOutcome::Failed(RefuelError::NotController),
+        };
+        record_refuel_result(target, result.clone());
+        assert_eq!(get_last_refuel_result(target), Some(result.clone()));
+        assert_eq!(get_last_refuel_result_all(), vec![(target, result)]);
+    }
+
+    #[test]
+    fn test_refuel_events() {
+        let target = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let event = |timestamp: u64| RefuelEvent {
+            timestamp,
+            target,
+            amount: 100,
+            target_balance_before: 10,
+            vault_balance_after: 1_000,
+            trigger: RefuelTrigger::Scheduled,
+        };
+        assert!(get_refuel_events(0, 10).is_empty());
+        assert!(get_refuel_events_between(0, 100, 10).is_empty());
+
+        for timestamp in [10, 20, 20, 30, 40] {
+            append_refuel_event(event(timestamp));
+        }
+        assert_eq!(get_refuel_events_count(), 5);
+        assert_eq!(
+            get_refuel_events(1, 2),
+            vec![(1, event(20)), (2, event(20))]
+        );
+        assert_eq!(get_refuel_events(4, 10), vec![(4, event(40))]);
+        assert_eq!(get_refuel_events(0, u64::MAX).len(), 5);
+
+        assert_eq!(
+            get_refuel_events_between(20, 40, 10),
+            vec![(1, event(20)), (2, event(20)), (3, event(30))]
+        );
+        assert_eq!(get_refuel_events_between(15, 40, 1), vec![(1, event(20))]);
+        assert!(get_refuel_events_between(41, 100, 10).is_empty());
+        assert_eq!(get_refuel_events_between(0, 11, 10), vec![(0, event(10))]);
+    }
+
+    #[test]
+    fn test_burn_rate() {
+        let target = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let day = 24 * 60 * 60 * 1_000_000_000;
+        update_burn_rate(target, 1_000, 10, day);
+        _record_observed_cycles(
+            target,
+            CycleObservation {
+                cycles: 1_000,
+                timestamp: day,
+            },
+        );
+        assert_eq!(get_burn_rate(target).unwrap().cycles_per_day, None);
+
+        let rate = update_burn_rate(target, 800, 10, 2 * day);
+        assert_eq!(rate.cycles_per_day, Some(200));
+        assert_eq!(get_burn_rate(target), Some(rate));
+        _record_observed_cycles(
+            target,
+            CycleObservation {
+                cycles: 800,
+                timestamp: 2 * day,
+            },
+        );
+
+        // deposited cycles are counted as observed
+        add_observed_cycles(target, 1_000);
+        let rate = update_burn_rate(target, 1_400, 10, 3 * day);
+        assert_eq!(rate.cycles_per_day, Some(300));
+    }
+
+    #[test]
+    fn test_next_predicted_refuel_secs() {
+        let target = RefuelTarget {
+            id: Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap(),
+            threshold: 1_000,
+            amount: 200,
+            strategy: None,
+            min_deposit: None,
+            max_deposit: None,
+        };
+        _put_refuel_target(&target, 0);
+        BURN_RATES.with(|m| {
+            m.borrow_mut().insert(
+                target.id.into(),
+                BurnRate {
+                    cycles_per_day: Some(100),
+                    idle_cycles_per_day: 0,
+                    updated_at: 0,
+                },
+            )
+        });
+        _record_observed_cycles(
+            target.id,
+            CycleObservation {
+                cycles: 2_000,
+                timestamp: 0,
+            },
+        );
+        assert_eq!(next_predicted_refuel_secs(0), None);
+
+        let res = PREDICTIVE_REFUEL_CONFIG.with(|m| {
+            m.borrow_mut().set(PredictiveRefuelConfig {
+                is_enabled: true,
+                runway_secs: 7 * 24 * 60 * 60,
+            })
+        });
+        res.unwrap();
+        assert_eq!(next_predicted_refuel_secs(0), Some(3 * 24 * 60 * 60));
+
+        // the observation is a day old
+        let day_nanos = 24 * 60 * 60 * 1000 * 1000000;
+        assert_eq!(
+            next_predicted_refuel_secs(day_nanos),
+            Some(2 * 24 * 60 * 60)
+        );
+        // the refuel is already due
+        assert_eq!(next_predicted_refuel_secs(4 * day_nanos), Some(0));
+
+        update_refuel_target_entry(target.id, |e| e.is_paused = true);
+        assert_eq!(next_predicted_refuel_secs(0), None);
+    }
+
+    #[test]
+    fn test_roles() {
+        let admin = Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap();
+        let operator = Principal::from_text("vsrdt-ryaaa-aaaao-a2muq-cai").unwrap();
+        assert!(!has_role(operator, Role::Operator));
+
+        _grant_role(admin, Role::Admin);
+        _grant_role(operator, Role::Depositor);
+        _grant_role(operator, Role::Operator);
+        _grant_role(operator, Role::Operator);
+        assert_eq!(get_roles(operator), vec![Role::Operator, Role::Depositor]);
+
+        assert!(has_role(admin, Role::Operator));
+        assert!(has_role(admin, Role::Depositor));
+        assert!(has_role(operator, Role::Operator));
+        assert!(!has_role(operator, Role::Admin));
+
+        _revoke_role(operator, Role::Operator);
+        assert!(!has_role(operator, Role::Operator));
+        _revoke_role(operator, Role::Depositor);
+        assert_eq!(get_role_members(), vec![(admin, vec![Role::Admin])]);
+    }
+
     #[test]
     #[should_panic(expected = "No metrics")]
     fn test_metric_when_no_monitor() {
@@ -0,0 +1,163 @@
+//! Estimation of the burn rates of targets to refuel them before they hit their thresholds
+use crate::types::{BurnRate, CycleObservation, RefuelTarget};
+
+const NANOS_PER_DAY: u128 = 24 * 60 * 60 * 1_000_000_000;
+const SECS_PER_DAY: u128 = 24 * 60 * 60;
+
+/// Cycles burned per day between the observation and the current balance
+/// NOTE: None if the balance increased, as cycles deposited by others cannot be told apart
+pub fn measured_burn_per_day(
+    prev: &CycleObservation,
+    cycles: u128,
+    timestamp: u64,
+) -> Option<u128> {
+    let elapsed = timestamp.checked_sub(prev.timestamp).filter(|e| *e > 0)?;
+    let burned = prev.cycles.checked_sub(cycles)?;
+    Some(burned.saturating_mul(NANOS_PER_DAY) / elapsed as u128)
+}
+
+/// Smooth the measured burn with the current rate by the average of them
+pub fn next_burn_rate(
+    current: Option<&BurnRate>,
+    measured: Option<u128>,
+    idle_cycles_per_day: u128,
+    now: u64,
+) -> BurnRate {
+    let current = current.and_then(|c| c.cycles_per_day);
+    let cycles_per_day = match (current, measured) {
+        (Some(c), Some(m)) => Some(c / 2 + m / 2),
+        (None, m) => m,
+        (c, None) => c,
+    };
+    BurnRate {
+        cycles_per_day,
+        idle_cycles_per_day,
+        updated_at: now,
+    }
+}
+
+/// Balance required to keep the target above the threshold during the runway
+pub fn required_balance(threshold: u128, rate: &BurnRate, runway_secs: u64) -> u128 {
+    let burned = rate.effective_per_day().saturating_mul(runway_secs as u128) / SECS_PER_DAY;
+    threshold.saturating_add(burned)
+}
+
+/// Cycles to deposit so that the balance covers the runway, bounded by the target
+pub fn runway_deposit(target: &RefuelTarget, balance: u128, required: u128) -> u128 {
+    let amount = target
+        .deposit_amount(balance)
+        .max(required.saturating_sub(balance));
+    amount.min(target.max_deposit.unwrap_or(u128::MAX))
+}
+
+/// Seconds until the balance is predicted to fall to the required balance
+pub fn secs_until_required(balance: u128, required: u128, rate: &BurnRate) -> Option<u64> {
+    let per_day = rate.effective_per_day();
+    if per_day == 0 {
+        return None;
+    }
+    let secs = balance
+        .saturating_sub(required)
+        .saturating_mul(SECS_PER_DAY)
+        / per_day;
+    Some(u64::try_from(secs).unwrap_or(u64::MAX))
+}
+
+#[cfg(test)]
+mod tests {
+    use candid::Principal;
+
+    use super::*;
+    use crate::types::RefuelStrategy;
+
+    const DAY_NANOS: u64 = 24 * 60 * 60 * 1_000_000_000;
+    const DAY_SECS: u64 = 24 * 60 * 60;
+
+    fn rate(cycles_per_day: u128, idle_cycles_per_day: u128) -> BurnRate {
+        BurnRate {
+            cycles_per_day: Some(cycles_per_day),
+            idle_cycles_per_day,
+            updated_at: 0,
+        }
+    }
+
+    #[test]
+    fn test_measured_burn_per_day() {
+        let prev = CycleObservation {
+            cycles: 1_000,
+            timestamp: DAY_NANOS,
+        };
+        assert_eq!(measured_burn_per_day(&prev, 800, 2 * DAY_NANOS), Some(200));
+        assert_eq!(
+            measured_burn_per_day(&prev, 900, DAY_NANOS + DAY_NANOS / 2),
+            Some(200)
+        );
+        // deposited by others
+        assert_eq!(measured_burn_per_day(&prev, 1_100, 2 * DAY_NANOS), None);
+        assert_eq!(measured_burn_per_day(&prev, 800, DAY_NANOS), None);
+        assert_eq!(measured_burn_per_day(&prev, 800, 0), None);
+    }
+
+    #[test]
+    fn test_next_burn_rate() {
+        let unmeasured = next_burn_rate(None, None, 10, 1);
+        assert_eq!(unmeasured.cycles_per_day, None);
+        assert_eq!(unmeasured.effective_per_day(), 10);
+        assert_eq!(
+            next_burn_rate(Some(&unmeasured), Some(100), 10, 1).cycles_per_day,
+            Some(100)
+        );
+        assert_eq!(
+            next_burn_rate(Some(&rate(100, 10)), Some(200), 10, 1).cycles_per_day,
+            Some(150)
+        );
+        assert_eq!(
+            next_burn_rate(Some(&rate(100, 10)), None, 20, 1),
+            rate(100, 20).with_updated_at(1)
+        );
+    }
+
+    #[test]
+    fn test_required_balance() {
+        assert_eq!(required_balance(1_000, &rate(100, 10), 7 * DAY_SECS), 1_700);
+        // idle burn is the minimum
+        assert_eq!(required_balance(1_000, &rate(0, 10), 7 * DAY_SECS), 1_070);
+        assert_eq!(required_balance(1_000, &rate(0, 0), 7 * DAY_SECS), 1_000);
+    }
+
+    #[test]
+    fn test_secs_until_required() {
+        assert_eq!(
+            secs_until_required(2_000, 1_700, &rate(100, 0)),
+            Some(3 * DAY_SECS)
+        );
+        assert_eq!(secs_until_required(1_500, 1_700, &rate(100, 0)), Some(0));
+        assert_eq!(secs_until_required(2_000, 1_700, &rate(0, 0)), None);
+    }
+
+    #[test]
+    fn test_runway_deposit() {
+        let target = RefuelTarget {
+            id: Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap(),
+            threshold: 1_000,
+            amount: 200,
+            strategy: None,
+            min_deposit: None,
+            max_deposit: None,
+        };
+        assert_eq!(runway_deposit(&target, 1_600, 1_700), 200);
+        assert_eq!(runway_deposit(&target, 1_000, 1_700), 700);
+        let bounded = RefuelTarget {
+            strategy: Some(RefuelStrategy::TopUpTo { level: 1_500 }),
+            max_deposit: Some(600),
+            ..target
+        };
+        assert_eq!(runway_deposit(&bounded, 1_000, 1_700), 600);
+    }
+
+    impl BurnRate {
+        fn with_updated_at(self, updated_at: u64) -> Self {
+            Self { updated_at, ..self }
+        }
+    }
+}
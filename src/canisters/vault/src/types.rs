@@ -85,6 +85,67 @@ pub struct RefuelTarget {
     pub id: Principal,
     pub amount: u128,
     pub threshold: u128,
+    /// `FixedAmount` if not set
+    pub strategy: Option<RefuelStrategy>,
+    pub min_deposit: Option<u128>,
+    pub max_deposit: Option<u128>,
+}
+impl RefuelTarget {
+    /// Cycles to deposit to the target with the balance below the threshold
+    pub fn deposit_amount(&self, balance: u128) -> u128 {
+        let amount = match self.strategy.unwrap_or(RefuelStrategy::FixedAmount) {
+            RefuelStrategy::FixedAmount => self.amount,
+            RefuelStrategy::TopUpTo { level } => level.saturating_sub(balance),
+            RefuelStrategy::PercentageOfDeficit { level, percent } => {
+                let deficit = level.saturating_sub(balance);
+                (deficit / 100)
+                    .saturating_mul(percent as u128)
+                    .saturating_add((deficit % 100) * percent as u128 / 100)
+            }
+        };
+        let amount = amount.max(self.min_deposit.unwrap_or_default());
+        amount.min(self.max_deposit.unwrap_or(u128::MAX))
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RefuelStrategy {
+    /// Deposit `amount` of the target
+    FixedAmount,
+    /// Deposit the deficit to the level
+    TopUpTo { level: u128 },
+    /// Deposit the percentage of the deficit to the level
+    PercentageOfDeficit { level: u128, percent: u32 },
+}
+
+/// `RefuelTarget` in the layout of the legacy vector, used only for the migration
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LegacyRefuelTarget {
+    pub id: Principal,
+    pub amount: u128,
+    pub threshold: u128,
+}
+impl From<LegacyRefuelTarget> for RefuelTarget {
+    fn from(legacy: LegacyRefuelTarget) -> Self {
+        Self {
+            id: legacy.id,
+            amount: legacy.amount,
+            threshold: legacy.threshold,
+            strategy: None,
+            min_deposit: None,
+            max_deposit: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct RefuelTargetEntry {
+    pub target: RefuelTarget,
+    pub label: String,
+    pub created_at: u64,
+    pub is_paused: bool,
+    /// Targets with higher priorities are refueled first
+    pub priority: u32,
 }
 
 #[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
@@ -93,6 +154,232 @@ pub struct ComponentMetricsSnapshot {
     pub cycles: u128,
 }
 
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct CycleObservation {
+    pub cycles: u128,
+    pub timestamp: u64,
+}
+
+/// Shares reserved by a withdrawal until its deposit completes, or restored on the next upgrade
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct PendingWithdrawal {
+    pub principal: Principal,
+    pub amount: Balance,
+    pub share: Index,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum WithdrawError {
+    InsufficientBalance {
+        withdrawable: Balance,
+    },
+    /// The shares are restored
+    DepositFailed(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum ShareAmount {
+    /// Converted to the shares at the current index and total supply
+    Balance(Balance),
+    Share(Index),
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum TransferShareError {
+    InsufficientShare { available: Index },
+    ZeroAmount,
+    SelfTransfer,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ShareTransfer {
+    pub timestamp: u64,
+    pub from: Principal,
+    pub to: Principal,
+    pub share: Index,
+    /// Balance of the shares at the time of the transfer
+    pub amount: Balance,
+    /// Set if transferred by an approved spender
+    pub spender: Option<Principal>,
+    pub memo: Option<Vec<u8>>,
+}
+
+/// Approval of `spender` by `owner`, logged apart from the transfers
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ShareApproval {
+    pub timestamp: u64,
+    pub owner: Principal,
+    pub spender: Principal,
+    pub share: Index,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Vec<u8>>,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct AllowanceKey {
+    pub owner: Principal,
+    pub spender: Principal,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct ShareAllowance {
+    pub share: Index,
+    pub expires_at: Option<u64>,
+}
+
+/// NOTE: Ordered by the depositor first, to list the deposits of a depositor
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub struct IcpDepositKey {
+    pub depositor: Principal,
+    /// Block of the transfer to the CMC made by the vault
+    pub top_up_block: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct IcpDeposit {
+    pub depositor: Principal,
+    pub notified_at: u64,
+    pub status: IcpDepositStatus,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum IcpDepositStatus {
+    /// Transferred to the CMC in `top_up_block` but not yet converted
+    Transferred {
+        top_up_block: u64,
+        amount_e8s: u64,
+    },
+    /// The CMC is being notified of `top_up_block` since `since`
+    Notifying {
+        top_up_block: u64,
+        amount_e8s: u64,
+        since: u64,
+    },
+    Completed {
+        top_up_block: u64,
+        cycles: u128,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum IcpDepositError {
+    /// The block has already been converted to shares
+    AlreadyCompleted {
+        cycles: u128,
+    },
+    /// Another notification of the deposit is in progress
+    Processing,
+    /// No deposit of the caller was transferred to the CMC in the block
+    NotFound,
+    /// The balance of the deposit account does not cover the fee
+    InsufficientDeposit {
+        balance_e8s: u64,
+    },
+    TransferFailed(String),
+    /// The deposit is kept at the CMC, notify the block again to retry
+    NotifyFailed(String),
+    /// The CMC refunded the deposit to the deposit account
+    Refunded(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum RefuelError {
+    /// The vault does not have enough cycles to deposit
+    OutOfCycles,
+    /// The vault is not a controller of the target, so its balance cannot be checked
+    NotController,
+    TargetNotFound,
+    Other(String),
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum RefuelOutcome {
+    Refueled { amount: u128 },
+    Skipped { balance: u128 },
+    Failed(RefuelError),
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct RefuelResult {
+    pub timestamp: u64,
+    pub outcome: RefuelOutcome,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub enum RefuelTrigger {
+    Scheduled,
+    Manual(Principal),
+    /// Scheduled by the predicted burn of a target
+    Predicted,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct RefuelEvent {
+    pub timestamp: u64,
+    pub target: Principal,
+    pub amount: u128,
+    pub target_balance_before: u128,
+    pub vault_balance_after: u128,
+    pub trigger: RefuelTrigger,
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct BurnRate {
+    /// Smoothed from successive balances of the target, None until measured
+    pub cycles_per_day: Option<u128>,
+    /// Reported by `canister_status`, the minimum of the burn rate
+    pub idle_cycles_per_day: u128,
+    pub updated_at: u64,
+}
+impl BurnRate {
+    pub fn effective_per_day(&self) -> u128 {
+        self.cycles_per_day
+            .unwrap_or_default()
+            .max(self.idle_cycles_per_day)
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct PredictiveRefuelConfig {
+    pub is_enabled: bool,
+    /// Targets are refueled before their balances are predicted to hit the threshold within this
+    pub runway_secs: u64,
+}
+impl Default for PredictiveRefuelConfig {
+    fn default() -> Self {
+        Self {
+            is_enabled: false,
+            runway_secs: 7 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// NOTE: Controllers have every role, `Admin` implies the others
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum Role {
+    /// Configures the vault, its targets and roles
+    Admin,
+    /// Operates refueling
+    Operator,
+    /// Supplies cycles when deposits are restricted
+    Depositor,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct AccessConfig {
+    /// Only depositors can `supply` if true
+    pub is_depositor_only: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone, Default, PartialEq, Eq, Debug)]
+pub struct Roles(pub Vec<Role>);
+impl Roles {
+    pub fn has(&self, role: Role) -> bool {
+        self.0.contains(&Role::Admin) || self.0.contains(&role)
+    }
+}
+
 #[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Debug)]
 pub struct CycleBalance {
     pub id: Principal,
@@ -134,6 +421,22 @@ impl Storable for RefuelTarget {
         Cow::Owned(Encode!(self).unwrap())
     }
 }
+impl Storable for LegacyRefuelTarget {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for RefuelTargetEntry {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
 impl Storable for ComponentMetricsSnapshot {
     fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
         Decode!(bytes.as_ref(), Self).unwrap()
@@ -142,6 +445,118 @@ impl Storable for ComponentMetricsSnapshot {
         Cow::Owned(Encode!(self).unwrap())
     }
 }
+impl Storable for CycleObservation {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for PendingWithdrawal {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for ShareApproval {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for ShareTransfer {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for IcpDepositKey {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for AllowanceKey {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for ShareAllowance {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for IcpDeposit {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for RefuelResult {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for RefuelEvent {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for BurnRate {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for PredictiveRefuelConfig {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for AccessConfig {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
+impl Storable for Roles {
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+}
 impl BoundedStorable for PrincipalStorable {
     const MAX_SIZE: u32 = 100;
     const IS_FIXED_SIZE: bool = false;
@@ -154,14 +569,68 @@ impl BoundedStorable for Index {
     const MAX_SIZE: u32 = 100;
     const IS_FIXED_SIZE: bool = false;
 }
-impl BoundedStorable for RefuelTarget {
+impl BoundedStorable for LegacyRefuelTarget {
     const MAX_SIZE: u32 = 100;
     const IS_FIXED_SIZE: bool = false;
 }
+/// NOTE: Labels must be truncated to fit
+impl BoundedStorable for RefuelTargetEntry {
+    const MAX_SIZE: u32 = 512;
+    const IS_FIXED_SIZE: bool = false;
+}
 impl BoundedStorable for ComponentMetricsSnapshot {
     const MAX_SIZE: u32 = 100;
     const IS_FIXED_SIZE: bool = false;
 }
+impl BoundedStorable for CycleObservation {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+impl BoundedStorable for PendingWithdrawal {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+impl BoundedStorable for ShareTransfer {
+    const MAX_SIZE: u32 = 300;
+    const IS_FIXED_SIZE: bool = false;
+}
+impl BoundedStorable for ShareApproval {
+    const MAX_SIZE: u32 = 300;
+    const IS_FIXED_SIZE: bool = false;
+}
+impl BoundedStorable for IcpDepositKey {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+impl BoundedStorable for AllowanceKey {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+impl BoundedStorable for ShareAllowance {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+impl BoundedStorable for IcpDeposit {
+    const MAX_SIZE: u32 = 200;
+    const IS_FIXED_SIZE: bool = false;
+}
+impl BoundedStorable for Roles {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+impl BoundedStorable for BurnRate {
+    const MAX_SIZE: u32 = 100;
+    const IS_FIXED_SIZE: bool = false;
+}
+impl BoundedStorable for RefuelEvent {
+    const MAX_SIZE: u32 = 300;
+    const IS_FIXED_SIZE: bool = false;
+}
+/// NOTE: Messages of `RefuelError::Other` must be truncated to fit
+impl BoundedStorable for RefuelResult {
+    const MAX_SIZE: u32 = 1024;
+    const IS_FIXED_SIZE: bool = false;
+}
 
 #[cfg(test)]
 mod tests {
@@ -228,7 +697,177 @@ mod tests {
             id: Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap(),
             threshold: 100,
             amount: 200,
+            strategy: Some(RefuelStrategy::TopUpTo { level: 300 }),
+            min_deposit: None,
+            max_deposit: Some(1_000),
         };
         assert_eq!(setting, RefuelTarget::from_bytes(setting.to_bytes()));
     }
+
+    #[test]
+    fn test_refuel_target_from_legacy_bytes() {
+        let legacy = LegacyRefuelTarget {
+            id: Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap(),
+            threshold: 100,
+            amount: 200,
+        };
+        assert_eq!(
+            RefuelTarget::from_bytes(legacy.to_bytes()),
+            RefuelTarget::from(legacy)
+        );
+    }
+
+    #[test]
+    fn test_deposit_amount() {
+        let target = RefuelTarget {
+            id: Principal::from_text("vvqfh-4aaaa-aaaao-a2mua-cai").unwrap(),
+            threshold: 500,
+            amount: 200,
+            strategy: None,
+            min_deposit: None,
+            max_deposit: None,
+        };
+        assert_eq!(target.deposit_amount(100), 200);
+
+        let top_up = RefuelTarget {
+            strategy: Some(RefuelStrategy::TopUpTo { level: 1_000 }),
+            ..target
+        };
+        assert_eq!(top_up.deposit_amount(100), 900);
+        assert_eq!(top_up.deposit_amount(1_200), 0);
+
+        let percentage = RefuelTarget {
+            strategy: Some(RefuelStrategy::PercentageOfDeficit {
+                level: 1_000,
+                percent: 50,
+            }),
+            ..target
+        };
+        assert_eq!(percentage.deposit_amount(100), 450);
+        assert_eq!(percentage.deposit_amount(999), 0);
+
+        // bounded
+        let bounded = RefuelTarget {
+            min_deposit: Some(100),
+            max_deposit: Some(500),
+            ..top_up
+        };
+        assert_eq!(bounded.deposit_amount(100), 500);
+        assert_eq!(bounded.deposit_amount(950), 100);
+
+        let huge = RefuelTarget {
+            strategy: Some(RefuelStrategy::PercentageOfDeficit {
+                level: u128::MAX,
+                percent: 200,
+            }),
+            ..target
+        };
+        assert_eq!(huge.deposit_amount(0), u128::MAX);
+    }
+
+    #[test]
+    fn test_refuel_event_max_size() {
+        let event = RefuelEvent {
+            timestamp: u64::MAX,
+            target: Principal::from_slice(&[0xff; 29]),
+            amount: u128::MAX,
+            target_balance_before: u128::MAX,
+            vault_balance_after: u128::MAX,
+            trigger: RefuelTrigger::Manual(Principal::from_slice(&[0xff; 29])),
+        };
+        assert!(event.to_bytes().len() as u32 <= RefuelEvent::MAX_SIZE);
+    }
+
+    #[test]
+    fn test_pending_withdrawal_max_size() {
+        let pending = PendingWithdrawal {
+            principal: Principal::from_slice(&[0xff; 29]),
+            amount: Balance::from(u128::MAX),
+            share: Index::from(u128::MAX),
+            created_at: u64::MAX,
+        };
+        assert!(pending.to_bytes().len() as u32 <= PendingWithdrawal::MAX_SIZE);
+    }
+
+    #[test]
+    fn test_refuel_target_entry_max_size() {
+        let entry = RefuelTargetEntry {
+            target: RefuelTarget {
+                id: Principal::from_slice(&[0xff; 29]),
+                threshold: u128::MAX,
+                amount: u128::MAX,
+                strategy: Some(RefuelStrategy::PercentageOfDeficit {
+                    level: u128::MAX,
+                    percent: u32::MAX,
+                }),
+                min_deposit: Some(u128::MAX),
+                max_deposit: Some(u128::MAX),
+            },
+            // labels are truncated to 64 bytes
+            label: "\u{10000}".repeat(16),
+            created_at: u64::MAX,
+            is_paused: true,
+            priority: u32::MAX,
+        };
+        assert!(entry.to_bytes().len() as u32 <= RefuelTargetEntry::MAX_SIZE);
+    }
+
+    #[test]
+    fn test_share_transfer_max_size() {
+        let transfer = ShareTransfer {
+            timestamp: u64::MAX,
+            from: Principal::from_slice(&[0xff; 29]),
+            to: Principal::from_slice(&[0xff; 29]),
+            share: Index::from(u128::MAX),
+            amount: Balance::from(u128::MAX),
+            spender: Some(Principal::from_slice(&[0xff; 29])),
+            memo: Some(vec![0xff; 32]),
+        };
+        assert!(transfer.to_bytes().len() as u32 <= ShareTransfer::MAX_SIZE);
+    }
+
+    #[test]
+    fn test_share_approval_max_size() {
+        let approval = ShareApproval {
+            timestamp: u64::MAX,
+            owner: Principal::from_slice(&[0xff; 29]),
+            spender: Principal::from_slice(&[0xff; 29]),
+            share: Index::from(u128::MAX),
+            expires_at: Some(u64::MAX),
+            memo: Some(vec![0xff; 32]),
+        };
+        assert!(approval.to_bytes().len() as u32 <= ShareApproval::MAX_SIZE);
+    }
+
+    #[test]
+    fn test_icp_deposit_max_size() {
+        let deposit = IcpDeposit {
+            depositor: Principal::from_slice(&[0xff; 29]),
+            notified_at: u64::MAX,
+            status: IcpDepositStatus::Completed {
+                top_up_block: u64::MAX,
+                cycles: u128::MAX,
+            },
+        };
+        assert!(deposit.to_bytes().len() as u32 <= IcpDeposit::MAX_SIZE);
+        let key = IcpDepositKey {
+            depositor: Principal::from_slice(&[0xff; 29]),
+            top_up_block: u64::MAX,
+        };
+        assert!(key.to_bytes().len() as u32 <= IcpDepositKey::MAX_SIZE);
+    }
+
+    #[test]
+    fn test_allowance_max_size() {
+        let key = AllowanceKey {
+            owner: Principal::from_slice(&[0xff; 29]),
+            spender: Principal::from_slice(&[0xff; 29]),
+        };
+        assert!(key.to_bytes().len() as u32 <= AllowanceKey::MAX_SIZE);
+        let allowance = ShareAllowance {
+            share: Index::from(u128::MAX),
+            expires_at: Some(u64::MAX),
+        };
+        assert!(allowance.to_bytes().len() as u32 <= ShareAllowance::MAX_SIZE);
+    }
 }